@@ -0,0 +1,69 @@
+use crate::error::ApiError;
+
+/// What a request wants to do with the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Looks at devices: `/status`, `/devices`, `/capabilities`, `/events`
+    /// and the changes sent over `/ws`
+    Read,
+    /// Changes devices: `/command`, `/commands`, `/ws`
+    Write,
+}
+
+/// Shared secrets that requests have to carry
+///
+/// With no tokens every request is let through, which is the default.
+/// Otherwise requests that write, and requests that read if `protect_reads`
+/// is set, need an `Authorization: Bearer <token>` header with one of the
+/// tokens.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    pub tokens: Vec<String>,
+    pub protect_reads: bool,
+}
+
+impl Auth {
+    pub fn new(token: &str) -> Self {
+        Self {
+            tokens: vec![token.to_string()],
+            protect_reads: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Checks the value of a request's `Authorization` header
+    pub fn check(&self, access: Access, authorization: Option<&str>) -> Result<(), ApiError> {
+        let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+        self.check_token(access, token)
+    }
+
+    /// Checks a bare token, for clients that can't set headers
+    pub fn check_token(&self, access: Access, token: Option<&str>) -> Result<(), ApiError> {
+        if !self.is_enabled() || (access == Access::Read && !self.protect_reads) {
+            return Ok(());
+        }
+        match token {
+            Some(token)
+                if self
+                    .tokens
+                    .iter()
+                    .any(|t| constant_time_eq(t.as_bytes(), token.trim().as_bytes())) =>
+            {
+                Ok(())
+            }
+            _ => Err(ApiError::Unauthorized),
+        }
+    }
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// doesn't give away how much of a token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use device::{Action, Device};
+
+use crate::command::{action_names, supports, MAX_TARGET};
+
+/// A physical input that controls a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Input {
+    /// A rotary encoder that moves the device up and down
+    Encoder { pin_a: i32, pin_b: i32 },
+    /// A button that reverses the device
+    ReverseButton { pin: i32 },
+}
+
+/// Describes what the device can do and what controls it
+///
+/// Lists the names of the actions the device accepts, the range its target
+/// can be set within, `null` if it can't be set, and the inputs bound to it
+/// in `Node::inputs`
+pub fn capabilities(device: &Device, inputs: &HashMap<Uuid, Vec<Input>>) -> Value {
+    let target = if supports(device, &Action::Set(0)) {
+        json!({ "min": 0, "max": MAX_TARGET })
+    } else {
+        Value::Null
+    };
+    json!({
+        "name": device.name,
+        "uuid": device.uuid,
+        "actions": action_names(device),
+        "target": target,
+        "inputs": inputs.get(&device.uuid).cloned().unwrap_or_default(),
+    })
+}
@@ -0,0 +1,335 @@
+use std::mem::discriminant;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use device::{Action, Device, Devices};
+
+use crate::error::ApiError;
+use crate::fade::{Fades, MAX_FADE};
+use crate::lookup::{DeviceSelector, Resolver};
+
+/// Highest target a device can be set to
+pub const MAX_TARGET: usize = 7;
+
+/// A single command aimed at one device
+///
+/// This is the JSON body accepted by `POST /command`, e.g.
+/// `{"uuid": "...", "action": "set", "target": 5}`. The device can be given
+/// by name with `"device"` instead of `"uuid"`, or a whole group of devices
+/// with `"group"`. A `Set` can fade to its target over `"duration_ms"`.
+#[derive(Debug, Deserialize)]
+pub struct CommandRequest {
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<usize>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    /// Only used over `/ws`, where the `Authorization` header can't be set
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Unix time in seconds, only needed when commands are signed
+    #[serde(default)]
+    pub ts: Option<u64>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub sig: Option<String>,
+}
+
+impl CommandRequest {
+    /// Checks the request the same way the query string version of
+    /// `/command` is checked, returning the device, the action and how long
+    /// to fade for
+    pub fn validate(&self) -> Result<(DeviceSelector, Action, Option<Duration>), ApiError> {
+        let target = check_target(self.target)?;
+        let action = parse_action(self.action.as_deref(), target)?;
+        let fade = check_fade(&action, self.duration_ms)?;
+        let selector = DeviceSelector::parse(
+            self.uuid.as_deref(),
+            self.device.as_deref(),
+            self.group.as_deref(),
+        )?;
+        Ok((selector, action, fade))
+    }
+
+    /// The fields as the name/value pairs that get signed, see `Signing`
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if let Some(uuid) = &self.uuid {
+            params.push(("uuid", uuid.clone()));
+        }
+        if let Some(device) = &self.device {
+            params.push(("device", device.clone()));
+        }
+        if let Some(group) = &self.group {
+            params.push(("group", group.clone()));
+        }
+        if let Some(action) = &self.action {
+            params.push(("action", action.clone()));
+        }
+        if let Some(target) = self.target {
+            params.push(("target", target.to_string()));
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            params.push(("duration_ms", duration_ms.to_string()));
+        }
+        if let Some(ts) = self.ts {
+            params.push(("ts", ts.to_string()));
+        }
+        if let Some(nonce) = &self.nonce {
+            params.push(("nonce", nonce.clone()));
+        }
+        if let Some(sig) = &self.sig {
+            params.push(("sig", sig.clone()));
+        }
+        params
+    }
+}
+
+/// Parses the `target` query parameter, an empty value counts as no target
+pub fn parse_target(target: Option<&str>) -> Result<Option<usize>, ApiError> {
+    match target {
+        Some(t_text) => match t_text.parse::<usize>() {
+            Ok(t_num) => check_target(Some(t_num)),
+            Err(_) => {
+                if !t_text.is_empty() {
+                    return Err(ApiError::InvalidField("target"));
+                }
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn check_target(target: Option<usize>) -> Result<Option<usize>, ApiError> {
+    match target {
+        Some(t_num) if t_num > MAX_TARGET => Err(ApiError::TargetOutOfRange),
+        _ => Ok(target),
+    }
+}
+
+/// Parses the `duration_ms` query parameter, an empty value counts as none
+pub fn parse_duration(duration_ms: Option<&str>) -> Result<Option<u64>, ApiError> {
+    match duration_ms {
+        Some(text) if !text.is_empty() => text
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| ApiError::InvalidField("duration_ms")),
+        _ => Ok(None),
+    }
+}
+
+/// Only a `Set` can fade
+pub fn check_fade(action: &Action, duration_ms: Option<u64>) -> Result<Option<Duration>, ApiError> {
+    if duration_ms.is_some() && !matches!(action, Action::Set(_)) {
+        return Err(ApiError::InvalidField("duration_ms"));
+    }
+    fade_duration(duration_ms)
+}
+
+/// Fades can't be longer than `MAX_FADE`
+pub fn fade_duration(duration_ms: Option<u64>) -> Result<Option<Duration>, ApiError> {
+    match duration_ms.map(Duration::from_millis) {
+        Some(duration) if duration > MAX_FADE => Err(ApiError::InvalidField("duration_ms")),
+        duration => Ok(duration),
+    }
+}
+
+pub fn parse_action(action: Option<&str>, target: Option<usize>) -> Result<Action, ApiError> {
+    match action {
+        Some(a) => Action::from_str(&a.to_lowercase(), target).map_err(|_| ApiError::UnknownAction),
+        None => Err(ApiError::MissingField("action")),
+    }
+}
+
+/// The name `parse_action` accepts for the action, e.g. `"set"` for `Set(3)`
+///
+/// The name is guessed from the action's `Debug` output, so it's only given
+/// if parsing it back gives the same kind of action.
+pub fn action_name(action: &Action) -> Option<String> {
+    let debug = format!("{:?}", action);
+    let name = debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    match Action::from_str(&name, Some(0)) {
+        Ok(parsed) if discriminant(&parsed) == discriminant(action) => Some(name),
+        _ => None,
+    }
+}
+
+/// The names of the device's actions, see `action_name`
+pub fn action_names(device: &Device) -> Vec<String> {
+    device
+        .get_available_actions()
+        .iter()
+        .filter_map(action_name)
+        .collect()
+}
+
+/// Has the selected device, or every device in the selected group, take the
+/// action
+///
+/// Returns the device's json after the action was taken, or for a group,
+/// which of its devices took the action and which were skipped. A single
+/// device that doesn't have the action fails with `UnsupportedAction`. With
+/// a `fade`, the devices' duty cycles ramp to their new values over it.
+pub fn apply(
+    devices: &Devices,
+    resolver: &Resolver,
+    fades: &Fades,
+    selector: &DeviceSelector,
+    action: Action,
+    fade: Option<Duration>,
+) -> Result<String, ApiError> {
+    let mut devices_guard = devices.devices.lock().unwrap();
+    apply_to(
+        devices_guard.as_mut_slice(),
+        resolver,
+        fades,
+        selector,
+        action,
+        fade,
+    )
+    .map(|applied| match applied {
+        Applied::Device(device) => device.to_json(),
+        Applied::Group(result) => result.to_string(),
+    })
+}
+
+/// Applies every command while holding the devices' lock once, so that all
+/// of the changes show up together
+///
+/// Each command gets its own entry in the returned list, holding either
+/// the device after the action was taken or the reason it wasn't. Commands
+/// that fail `check` aren't applied.
+pub fn apply_all(
+    devices: &Devices,
+    resolver: &Resolver,
+    fades: &Fades,
+    commands: &[CommandRequest],
+    check: impl Fn(&CommandRequest) -> Result<(), ApiError>,
+) -> Vec<Value> {
+    let mut devices_guard = devices.devices.lock().unwrap();
+    commands
+        .iter()
+        .map(|command| {
+            let result = check(command).and_then(|_| command.validate()).and_then(
+                |(selector, action, fade)| {
+                    apply_to(
+                        devices_guard.as_mut_slice(),
+                        resolver,
+                        fades,
+                        &selector,
+                        action,
+                        fade,
+                    )
+                },
+            );
+            match result {
+                Ok(Applied::Device(device)) => json!({ "device": device }),
+                Ok(Applied::Group(result)) => result,
+                Err(error) => {
+                    let mut entry = error.to_value();
+                    entry["uuid"] = json!(command.uuid);
+                    entry["device"] = json!(command.device);
+                    entry["group"] = json!(command.group);
+                    entry
+                }
+            }
+        })
+        .collect()
+}
+
+/// Whether the device has an action of the same kind, whatever its target
+pub fn supports(device: &Device, action: &Action) -> bool {
+    device
+        .get_available_actions()
+        .iter()
+        .any(|available| discriminant(available) == discriminant(action))
+}
+
+enum Applied<'a> {
+    Device(&'a Device),
+    Group(Value),
+}
+
+fn apply_to<'a>(
+    devices: &'a mut [Device],
+    resolver: &Resolver,
+    fades: &Fades,
+    selector: &DeviceSelector,
+    action: Action,
+    fade: Option<Duration>,
+) -> Result<Applied<'a>, ApiError> {
+    fades.check(fade)?;
+    let group = match selector {
+        DeviceSelector::Group(group) => group,
+        _ => {
+            let index = resolver.resolve(devices, selector)?;
+            let device = &mut devices[index];
+            if !supports(device, &action) {
+                return Err(ApiError::UnsupportedAction);
+            }
+            if let Some(duration) = fade {
+                fades.request(device.uuid, duration);
+            }
+            let _ = device.take_action(action);
+            return Ok(Applied::Device(device));
+        }
+    };
+    let members = resolver.group(group)?;
+    let mut result = apply_each(
+        devices,
+        members.iter().map(|uuid| (*uuid, action.clone())),
+        fades,
+        fade,
+    );
+    result["group"] = json!(group);
+    Ok(Applied::Group(result))
+}
+
+/// Has each device take its action, skipping devices that are missing or
+/// don't support it
+///
+/// Returns `{"applied": [...], "skipped": [...]}`, with the devices that took
+/// their action and the reason each of the others didn't
+pub(crate) fn apply_each(
+    devices: &mut [Device],
+    actions: impl IntoIterator<Item = (Uuid, Action)>,
+    fades: &Fades,
+    fade: Option<Duration>,
+) -> Value {
+    let mut applied = Vec::new();
+    let mut skipped = Vec::new();
+    for (uuid, action) in actions {
+        match devices.iter_mut().find(|device| device.uuid == uuid) {
+            Some(device) if supports(device, &action) => {
+                if let Some(duration) = fade {
+                    fades.request(device.uuid, duration);
+                }
+                let _ = device.take_action(action);
+                applied.push(json!(device));
+            }
+            Some(device) => skipped.push(json!({
+                "uuid": device.uuid,
+                "name": device.name,
+                "reason": ApiError::UnsupportedAction.code(),
+            })),
+            None => skipped.push(json!({ "uuid": uuid, "reason": ApiError::UnknownUuid.code() })),
+        }
+    }
+    json!({
+        "applied": applied,
+        "skipped": skipped,
+    })
+}
@@ -0,0 +1,255 @@
+use std::{
+    fmt,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// How often `Connection::run` looks at the link
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the connection manager needs from the wifi driver
+///
+/// Implemented for the node's own wifi by `wifi::NodeWifi`, and small enough
+/// to implement for a mock.
+pub trait WifiLink {
+    type Error: fmt::Display;
+
+    /// Starts joining the current network, without waiting for it
+    fn connect(&mut self) -> Result<(), Self::Error>;
+    fn disconnect(&mut self) -> Result<(), Self::Error>;
+    /// Whether a network has been joined
+    fn is_connected(&self) -> Result<bool, Self::Error>;
+    /// Whether the node has an address on the network
+    fn is_up(&self) -> Result<bool, Self::Error>;
+    /// Switches to the network to try next, after one couldn't be joined
+    fn next_network(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Where the connection is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Trying to join a network
+    Connecting,
+    /// Joined, but waiting for an address
+    Connected,
+    /// Joined and has an address, so the node can be reached
+    GotIp,
+    /// Was joined and dropped off, about to try again
+    Lost,
+    /// Waiting this long before trying again, after failing to join
+    BackingOff(Duration),
+}
+
+/// A change from one `ConnectionState` to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+    /// Times the connection has come back since the node first got on the
+    /// network
+    pub reconnects: u32,
+    /// Attempts to join that failed since the last one that didn't
+    pub failures: u32,
+}
+
+/// How long to wait before trying to join again, doubling with each failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// The wait after the first failure
+    pub initial: Duration,
+    /// The longest wait, however many failures there have been
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl Backoff {
+    pub fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(16);
+        self.initial.saturating_mul(1 << doublings).min(self.max)
+    }
+}
+
+type Callback = Box<dyn FnMut(&ConnectionEvent) + Send>;
+
+/// Hands out connection changes to anything that subscribed to them or
+/// registered a callback, e.g. for a status LED
+#[derive(Clone, Default)]
+pub struct ConnectionEvents {
+    subscribers: Arc<Mutex<Vec<Sender<ConnectionEvent>>>>,
+    callbacks: Arc<Mutex<Vec<Callback>>>,
+}
+
+impl ConnectionEvents {
+    pub fn subscribe(&self) -> Receiver<ConnectionEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Calls `callback` on every change, on the thread managing the
+    /// connection, so it should return quickly
+    pub fn on_change(&self, callback: impl FnMut(&ConnectionEvent) + Send + 'static) {
+        self.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    pub fn publish(&self, event: ConnectionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event).is_ok());
+        for callback in self.callbacks.lock().unwrap().iter_mut() {
+            callback(&event);
+        }
+    }
+}
+
+/// Keeps the node on the network, reconnecting with `Backoff` when it drops
+pub struct Connection<L: WifiLink> {
+    link: L,
+    backoff: Backoff,
+    /// How long joining and getting an address can take before the attempt
+    /// counts as failed
+    timeout: Duration,
+    events: ConnectionEvents,
+    state: ConnectionState,
+    /// When the current attempt or wait started
+    since: Instant,
+    failures: u32,
+    reconnects: u32,
+    has_been_up: bool,
+}
+
+impl<L: WifiLink> Connection<L> {
+    /// Starts out `Connecting`, so a link that's already joined moves
+    /// straight on to `Connected` and `GotIp`
+    pub fn new(link: L, backoff: Backoff, timeout: Duration, events: ConnectionEvents) -> Self {
+        Self {
+            link,
+            backoff,
+            timeout,
+            events,
+            state: ConnectionState::Connecting,
+            since: Instant::now(),
+            failures: 0,
+            reconnects: 0,
+            has_been_up: false,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Steps the connection forever, never returns
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.step(Instant::now());
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Looks at the link once and moves to the next state if it's time to
+    ///
+    /// Takes the time so that it can be driven by a test clock.
+    pub fn step(&mut self, now: Instant) {
+        let connected = Self::check(self.link.is_connected());
+        match self.state {
+            ConnectionState::Connecting => {
+                if connected {
+                    self.change(ConnectionState::Connected, now);
+                } else if now.duration_since(self.since) >= self.timeout {
+                    self.fail(now);
+                }
+            }
+            ConnectionState::Connected => {
+                if !connected {
+                    self.change(ConnectionState::Lost, now);
+                } else if Self::check(self.link.is_up()) {
+                    if self.has_been_up {
+                        self.reconnects += 1;
+                    }
+                    self.has_been_up = true;
+                    self.failures = 0;
+                    self.change(ConnectionState::GotIp, now);
+                } else if now.duration_since(self.since) >= self.timeout {
+                    self.fail(now);
+                }
+            }
+            ConnectionState::GotIp => {
+                if !connected {
+                    self.change(ConnectionState::Lost, now);
+                }
+            }
+            ConnectionState::Lost => self.start_attempt(now),
+            ConnectionState::BackingOff(delay) => {
+                if connected {
+                    self.change(ConnectionState::Connected, now);
+                } else if now.duration_since(self.since) >= delay {
+                    self.start_attempt(now);
+                }
+            }
+        }
+    }
+
+    fn start_attempt(&mut self, now: Instant) {
+        if let Err(e) = self.link.connect() {
+            log::warn!("Couldn't start connecting: {}", e);
+        }
+        self.change(ConnectionState::Connecting, now);
+    }
+
+    /// Gives up on the attempt, moving on to the next network and waiting
+    /// before trying it
+    fn fail(&mut self, now: Instant) {
+        self.failures += 1;
+        if let Err(e) = self.link.disconnect() {
+            log::warn!("Couldn't disconnect: {}", e);
+        }
+        if let Err(e) = self.link.next_network() {
+            log::warn!("Couldn't switch networks: {}", e);
+        }
+        let delay = self.backoff.delay(self.failures);
+        self.change(ConnectionState::BackingOff(delay), now);
+    }
+
+    fn change(&mut self, to: ConnectionState, now: Instant) {
+        let event = ConnectionEvent {
+            from: self.state,
+            to,
+            reconnects: self.reconnects,
+            failures: self.failures,
+        };
+        log::info!("Connection {:?} -> {:?}", event.from, event.to);
+        self.state = to;
+        self.since = now;
+        self.events.publish(event);
+    }
+
+    /// Counts a link that can't be asked as a link that's down
+    fn check<E: fmt::Display>(answer: Result<bool, E>) -> bool {
+        answer.unwrap_or_else(|e| {
+            log::warn!("Couldn't check the connection: {}", e);
+            false
+        })
+    }
+}
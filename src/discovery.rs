@@ -0,0 +1,105 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+};
+
+use serde_json::{json, Value};
+
+use device::Devices;
+
+use crate::auth::{Access, Auth};
+use crate::command::action_names;
+use crate::API_VERSION;
+
+/// What a "who is there" packet has to say, surrounding whitespace aside
+pub const PROBE: &str = "who is there";
+
+/// What the node says about itself in reply to `PROBE`
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub hostname: String,
+    pub http_port: u16,
+    pub events_port: u16,
+}
+
+/// Answers `PROBE`s broadcast to `port` with the node's identity, address
+/// and devices
+///
+/// The reply goes straight back to whoever asked, so this works on networks
+/// that filter the multicast mDNS relies on. Anything other than a probe is
+/// ignored. When `Auth::protect_reads` is set, the devices are only counted,
+/// as the prober has no way to show a token. Never returns unless the port
+/// can't be bound.
+pub fn serve_discovery(
+    devices: Devices,
+    identity: Identity,
+    auth: Auth,
+    port: u16,
+) -> io::Result<()> {
+    let list_devices = auth.check_token(Access::Read, None).is_ok();
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let mut buf = [0; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Couldn't receive discovery probe: {}", e);
+                continue;
+            }
+        };
+        if std::str::from_utf8(&buf[..len]).map(str::trim) != Ok(PROBE) {
+            continue;
+        }
+        let ip = match local_ip(from) {
+            Ok(ip) => ip,
+            Err(e) => {
+                log::warn!("Couldn't tell which address {} reached us on: {}", from, e);
+                continue;
+            }
+        };
+        let reply = reply(&devices, &identity, ip, list_devices).to_string();
+        if let Err(e) = socket.send_to(reply.as_bytes(), from) {
+            log::warn!("Couldn't answer discovery probe from {}: {}", from, e);
+        }
+    }
+}
+
+/// The node's address on the interface `peer` is reached through
+///
+/// The socket answering probes is bound to every interface, so it can't say
+/// which address it was reached on. Connecting a UDP socket sends nothing,
+/// it only picks the route.
+fn local_ip(peer: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn reply(devices: &Devices, identity: &Identity, ip: IpAddr, list_devices: bool) -> Value {
+    let devices = devices.devices.lock().unwrap();
+    let mut reply = json!({
+        "name": identity.name,
+        "hostname": identity.hostname,
+        "api": API_VERSION,
+        "ip": ip,
+        "http_port": identity.http_port,
+        "events_port": identity.events_port,
+        "device_count": devices.len(),
+    });
+    if !list_devices {
+        return reply;
+    }
+    let devices: Vec<Value> = devices
+        .iter()
+        .map(|device| {
+            json!({
+                "name": device.name,
+                "uuid": device.uuid,
+                "actions": action_names(device),
+            })
+        })
+        .collect();
+    reply["devices"] = json!(devices);
+    reply
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+
+use esp_idf_sys::EspError;
+use serde_json::{json, Value};
+
+use crate::command::MAX_TARGET;
+
+/// Reasons an HTTP request to the node can fail
+///
+/// Sent back to the client as JSON, e.g.
+/// `{"error": "unknown_uuid", "message": "Uuid not found among devices", "field": "uuid"}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiError {
+    /// The request had no query string at all
+    MissingQuery,
+    /// A required field wasn't given
+    MissingField(&'static str),
+    /// A field was given but couldn't be parsed
+    InvalidField(&'static str),
+    /// The request body wasn't the expected JSON
+    InvalidJson,
+    /// The request body was larger than the node will read
+    BodyTooLarge,
+    /// The action name isn't one a device knows
+    UnknownAction,
+    /// The device doesn't have the action
+    UnsupportedAction,
+    /// The target is outside of `0..=MAX_TARGET`
+    TargetOutOfRange,
+    /// No device has the given uuid
+    UnknownUuid,
+    /// No device has the given name
+    UnknownDevice,
+    /// More than one device has the given name
+    AmbiguousDevice,
+    /// No group has the given name
+    UnknownGroup,
+    /// No scene has the given name
+    UnknownScene,
+    /// The request didn't carry a valid token
+    Unauthorized,
+    /// The command's signature doesn't match
+    BadSignature,
+    /// The command's timestamp is too old or its nonce was already used
+    Replayed,
+    /// Signed commands can't be checked until the clock is set
+    ClockNotSet,
+    /// So many signed commands came in within the window that no more
+    /// nonces can be remembered until the oldest expire
+    TooManyCommands,
+    /// The change couldn't be saved to flash
+    StorageFailed,
+    /// A fade was asked for, but the firmware doesn't run the loop that
+    /// carries fades out
+    FadesNotRunning,
+}
+
+impl ApiError {
+    /// The HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::MissingQuery
+            | ApiError::MissingField(_)
+            | ApiError::InvalidField(_)
+            | ApiError::InvalidJson => 400,
+            ApiError::Unauthorized | ApiError::BadSignature | ApiError::Replayed => 401,
+            ApiError::UnknownUuid
+            | ApiError::UnknownDevice
+            | ApiError::UnknownGroup
+            | ApiError::UnknownScene => 404,
+            ApiError::AmbiguousDevice => 409,
+            ApiError::BodyTooLarge => 413,
+            ApiError::UnknownAction
+            | ApiError::UnsupportedAction
+            | ApiError::TargetOutOfRange
+            | ApiError::FadesNotRunning => 422,
+            ApiError::StorageFailed => 500,
+            ApiError::ClockNotSet | ApiError::TooManyCommands => 503,
+        }
+    }
+
+    /// Machine readable name of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingQuery => "missing_query",
+            ApiError::MissingField(_) => "missing_field",
+            ApiError::InvalidField(_) => "invalid_field",
+            ApiError::InvalidJson => "invalid_json",
+            ApiError::BodyTooLarge => "body_too_large",
+            ApiError::UnknownAction => "unknown_action",
+            ApiError::UnsupportedAction => "unsupported_action",
+            ApiError::TargetOutOfRange => "target_out_of_range",
+            ApiError::UnknownUuid => "unknown_uuid",
+            ApiError::UnknownDevice => "unknown_device",
+            ApiError::AmbiguousDevice => "ambiguous_device",
+            ApiError::UnknownGroup => "unknown_group",
+            ApiError::UnknownScene => "unknown_scene",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::BadSignature => "bad_signature",
+            ApiError::Replayed => "replayed",
+            ApiError::ClockNotSet => "clock_not_set",
+            ApiError::TooManyCommands => "too_many_commands",
+            ApiError::StorageFailed => "storage_failed",
+            ApiError::FadesNotRunning => "fades_not_running",
+        }
+    }
+
+    /// The request field the error is about, if any
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            ApiError::MissingField(field) | ApiError::InvalidField(field) => Some(*field),
+            ApiError::UnknownAction | ApiError::UnsupportedAction => Some("action"),
+            ApiError::TargetOutOfRange => Some("target"),
+            ApiError::UnknownUuid => Some("uuid"),
+            ApiError::BadSignature => Some("sig"),
+            ApiError::Replayed => Some("nonce"),
+            ApiError::UnknownDevice | ApiError::AmbiguousDevice => Some("device"),
+            ApiError::UnknownGroup => Some("group"),
+            ApiError::UnknownScene => Some("name"),
+            ApiError::FadesNotRunning => Some("duration_ms"),
+            ApiError::MissingQuery
+            | ApiError::InvalidJson
+            | ApiError::BodyTooLarge
+            | ApiError::Unauthorized
+            | ApiError::ClockNotSet
+            | ApiError::TooManyCommands
+            | ApiError::StorageFailed => None,
+        }
+    }
+
+    /// Human readable description of the error
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::MissingQuery => "No query given".to_string(),
+            ApiError::MissingField(field) => format!("No {} given", field),
+            ApiError::InvalidField(field) => format!("Bad {} given", field),
+            ApiError::InvalidJson => "Bad JSON body given".to_string(),
+            ApiError::BodyTooLarge => "Body too large".to_string(),
+            ApiError::UnknownAction => "Bad Action name given".to_string(),
+            ApiError::UnsupportedAction => "Device doesn't support that action".to_string(),
+            ApiError::TargetOutOfRange => format!("Target must be >= 0 & <= {}", MAX_TARGET),
+            ApiError::UnknownUuid => "Uuid not found among devices".to_string(),
+            ApiError::UnknownDevice => "Device name not found".to_string(),
+            ApiError::AmbiguousDevice => "More than one device has that name".to_string(),
+            ApiError::UnknownGroup => "Group name not found".to_string(),
+            ApiError::UnknownScene => "Scene name not found".to_string(),
+            ApiError::Unauthorized => "Missing or invalid token".to_string(),
+            ApiError::BadSignature => "Signature doesn't match".to_string(),
+            ApiError::Replayed => "Command is too old or was already used".to_string(),
+            ApiError::ClockNotSet => "Clock isn't set yet".to_string(),
+            ApiError::TooManyCommands => "Too many commands, try again shortly".to_string(),
+            ApiError::StorageFailed => "Couldn't save the change".to_string(),
+            ApiError::FadesNotRunning => "This node can't fade".to_string(),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        json!({
+            "error": self.code(),
+            "message": self.message(),
+            "field": self.field(),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Reasons `Node::run` can fail
+///
+/// Returned instead of panicking so that firmware can fall back to something
+/// else, e.g. an access point, rather than rebooting over and over
+#[derive(Debug)]
+pub enum NodeError {
+    /// Taking the system event loop or nvs partition, or opening nvs, failed
+    System(EspError),
+    /// The node's settings can't be used, e.g. an ssid longer than 32 bytes
+    Config(&'static str),
+    /// The wifi driver couldn't be created, configured, started or connected
+    Wifi(EspError),
+    /// The http server couldn't be started or a handler couldn't be registered
+    Server(EspError),
+    /// The MQTT client couldn't be created, e.g. because of a bad broker url
+    Mqtt(EspError),
+    /// A background thread couldn't be started
+    Spawn(std::io::Error),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::System(e) => write!(f, "System error: {}", e),
+            NodeError::Config(message) => write!(f, "Bad configuration: {}", message),
+            NodeError::Wifi(e) => write!(f, "Wifi error: {}", e),
+            NodeError::Server(e) => write!(f, "Server error: {}", e),
+            NodeError::Mqtt(e) => write!(f, "MQTT error: {}", e),
+            NodeError::Spawn(e) => write!(f, "Couldn't start thread: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
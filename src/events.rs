@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use device::Devices;
+
+use crate::auth::{Access, Auth};
+use crate::error::ApiError;
+use crate::query::Query;
+use crate::THREAD_STACK_SIZE;
+
+/// Most event streams that can be open at once, each one holds a socket
+const MAX_CLIENTS: usize = 4;
+/// How often a comment is sent down an idle stream to keep it open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How long a write to a stream can take before the client counts as gone,
+/// e.g. a phone that went to sleep with the page open
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most changes held for a subscriber that hasn't taken them yet
+const SUBSCRIBER_QUEUE: usize = 32;
+
+/// A subscriber's end of its queue, and whether changes are being dropped
+/// because it fell behind
+struct Subscriber {
+    sender: SyncSender<DeviceChange>,
+    lagging: bool,
+}
+
+/// A device whose json changed since it was last looked at
+#[derive(Debug, Clone)]
+pub struct DeviceChange {
+    pub uuid: Uuid,
+    pub json: String,
+}
+
+/// Hands out device changes to anything that subscribed to them
+///
+/// Changes are found by `watch`, which compares every device's json against
+/// what it saw last time, so it catches changes no matter where they came
+/// from: http commands, encoders or the reverse button. Each subscriber
+/// holds at most `SUBSCRIBER_QUEUE` changes, the ones past that are dropped
+/// for it until it catches up, so a stuck subscriber can't use up the heap.
+#[derive(Clone, Default)]
+pub struct DeviceEvents {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    wake: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl DeviceEvents {
+    pub fn subscribe(&self) -> Receiver<DeviceChange> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_QUEUE);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            sender,
+            lagging: false,
+        });
+        receiver
+    }
+
+    /// Sends the change to every subscriber, forgetting the ones that hung up
+    /// and skipping the ones whose queue is full
+    pub fn publish(&self, change: DeviceChange) {
+        self.subscribers.lock().unwrap().retain_mut(|subscriber| {
+            match subscriber.sender.try_send(change.clone()) {
+                Ok(()) => {
+                    subscriber.lagging = false;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    if !subscriber.lagging {
+                        log::warn!("A device events subscriber fell behind, dropping changes");
+                        subscriber.lagging = true;
+                    }
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Has `watch` look for changes right away instead of at its next interval
+    pub fn notify(&self) {
+        let (woken, condvar) = &*self.wake;
+        *woken.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    fn wait(&self, timeout: Duration) {
+        let (woken, condvar) = &*self.wake;
+        let guard = woken.lock().unwrap();
+        let (mut guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |woken| !*woken)
+            .unwrap();
+        *guard = false;
+    }
+
+    /// Publishes a `DeviceChange` for every device whose json changes
+    ///
+    /// Checks every `interval` or whenever `notify` is called. Never returns,
+    /// so it should be given its own thread.
+    pub fn watch(&self, devices: Devices, interval: Duration) {
+        let mut last_seen: HashMap<Uuid, String> = HashMap::new();
+        loop {
+            let mut changes = Vec::new();
+            {
+                for device in devices.devices.lock().unwrap().iter() {
+                    let json = device.to_json();
+                    let last = last_seen.insert(device.uuid, json.clone());
+                    if last.is_some() && last.as_ref() != Some(&json) {
+                        changes.push(DeviceChange {
+                            uuid: device.uuid,
+                            json,
+                        });
+                    }
+                }
+            }
+            for change in changes {
+                self.publish(change);
+            }
+            self.wait(interval);
+        }
+    }
+}
+
+/// Serves `GET /events` as a Server-Sent Events stream on its own port
+///
+/// The http server runs every handler on a single task, so a stream that
+/// stays open would block every other request. Each stream here gets its own
+/// thread instead. Never returns unless the port can't be bound.
+pub fn serve_events(events: DeviceEvents, auth: Auth, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Couldn't accept events connection: {}", e);
+                continue;
+            }
+        };
+        if clients.load(Ordering::SeqCst) >= MAX_CLIENTS {
+            let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+            continue;
+        }
+        clients.fetch_add(1, Ordering::SeqCst);
+        let events = events.clone();
+        let auth = auth.clone();
+        let clients_clone = clients.clone();
+        let spawned = thread::Builder::new()
+            .stack_size(THREAD_STACK_SIZE)
+            .spawn(move || {
+                if let Err(e) = stream_events(stream, &events, &auth) {
+                    log::info!("Events stream closed: {}", e);
+                }
+                clients_clone.fetch_sub(1, Ordering::SeqCst);
+            });
+        if spawned.is_err() {
+            clients.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    Ok(())
+}
+
+fn stream_events(mut stream: TcpStream, events: &DeviceEvents, auth: &Auth) -> io::Result<()> {
+    let head = read_request_head(&mut stream)?;
+    if !head.starts_with("GET /events") {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+    if let Err(error) = check_auth(&head, auth) {
+        let body = error.to_json();
+        stream.write_all(
+            format!(
+                "HTTP/1.1 401 Unauthorized\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )?;
+        return Ok(());
+    }
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: keep-alive\r\n\
+        Access-Control-Allow-Origin: *\r\n\r\n",
+    )?;
+    let changes = events.subscribe();
+    loop {
+        match changes.recv_timeout(KEEP_ALIVE) {
+            Ok(change) => {
+                stream.write_all(format!("event: device\ndata: {}\n\n", change.json).as_bytes())?
+            }
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Checks the request's `Authorization` header, or its `token` query
+/// parameter since browsers can't set headers on an `EventSource`
+fn check_auth(head: &str, auth: &Auth) -> Result<(), ApiError> {
+    let authorization = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("authorization") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    });
+    if authorization.is_some() {
+        return auth.check(Access::Read, authorization);
+    }
+    let path = head.split_whitespace().nth(1).unwrap_or_default();
+    auth.check_token(Access::Read, Query::from_uri(path).get("token"))
+}
+
+/// Reads up until the blank line that ends the request's headers
+fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut head = Vec::new();
+    let mut buf = [0; 128];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 1024 {
+        let count = stream.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..count]);
+    }
+    stream.set_read_timeout(None)?;
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+/// Longest fade a command can ask for
+pub const MAX_FADE: Duration = Duration::from_secs(10 * 60);
+
+/// Fades asked for by commands, waiting to be picked up by the duty cycle
+/// loop, see `DevicesDutyCycles::update_duty_cycles_with`
+///
+/// A fade has to be requested while holding the devices' lock, right before
+/// the device takes its action, so that the loop sees both together.
+/// Fades are turned away until the loop is running, as nothing else would
+/// carry them out.
+#[derive(Debug, Clone, Default)]
+pub struct Fades {
+    requested: Arc<Mutex<HashMap<Uuid, Duration>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Fades {
+    /// Has the device's next duty cycle change ramp over `duration`
+    pub fn request(&self, uuid: Uuid, duration: Duration) {
+        self.requested.lock().unwrap().insert(uuid, duration);
+    }
+
+    /// Fails on a fade when no loop is running to carry it out
+    pub fn check(&self, fade: Option<Duration>) -> Result<(), ApiError> {
+        match fade {
+            Some(_) if !self.running.load(Ordering::Relaxed) => Err(ApiError::FadesNotRunning),
+            _ => Ok(()),
+        }
+    }
+
+    /// Called by the duty cycle loop as it starts
+    pub(crate) fn start(&self) {
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn take(&self, uuid: &Uuid) -> Option<Duration> {
+        self.requested.lock().unwrap().remove(uuid)
+    }
+}
+
+/// A driver's duty cycle moving from one value to another
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fade {
+    from: u32,
+    to: u32,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    pub(crate) fn new(from: u32, to: u32, start: Instant, duration: Duration) -> Self {
+        Self {
+            from,
+            to,
+            start,
+            duration,
+        }
+    }
+
+    /// The duty cycle the driver should be at by `now`
+    pub(crate) fn duty_at(&self, now: Instant) -> u32 {
+        if self.is_done(now) {
+            return self.to;
+        }
+        let elapsed = now.duration_since(self.start).as_millis() as i64;
+        let total = self.duration.as_millis() as i64;
+        let change = (self.to as i64 - self.from as i64) * elapsed / total;
+        (self.from as i64 + change) as u32
+    }
+
+    pub(crate) fn is_done(&self, now: Instant) -> bool {
+        now.duration_since(self.start) >= self.duration
+    }
+}
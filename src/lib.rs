@@ -2,15 +2,14 @@ use std::{
     collections::HashMap,
     default::Default,
     sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
 use heapless;
 use uuid::Uuid;
 
 pub use embedded_svc::http::Method;
-use embedded_svc::{http::server::Request, io::Write};
 pub use esp_idf_hal::gpio::{AnyInputPin, InputPin, PinDriver};
 pub use esp_idf_hal::ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver};
 pub use esp_idf_hal::pcnt::Pcnt;
@@ -19,32 +18,160 @@ use esp_idf_hal::{delay::Delay, modem::Modem, peripherals::Peripherals, units::H
 use esp_idf_svc::http::server::Configuration as SVC_Configuration;
 pub use esp_idf_svc::io::EspIOError;
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    http::server::{EspHttpConnection, EspHttpServer},
-    nvs::EspDefaultNvsPartition,
-    wifi::{ClientConfiguration, Configuration, EspWifi},
+    eventloop::EspSystemEventLoop, http::server::EspHttpServer, nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
 };
 //pub use esp_idf_hal::ledc::{config::LedcDriver, LedcTimerDriver, TimerConfig};
 
 pub use device;
 use device::{Action, Device, Devices};
 
+pub mod auth;
+pub mod capabilities;
+pub mod command;
+pub mod connection;
+pub mod discovery;
 pub mod encoder;
+pub mod error;
+pub mod events;
+pub mod fade;
+pub mod lookup;
+#[cfg(feature = "mdns")]
+mod mdns;
+pub mod mqtt;
+pub mod output;
+pub mod provision;
+pub mod query;
+pub mod scenes;
+mod server;
+pub mod signing;
+pub mod state;
+pub mod storage;
 pub mod updaters;
+pub mod wifi;
+#[cfg(feature = "websocket")]
+mod ws;
+pub use auth::Auth;
+pub use capabilities::Input;
+use connection::{Backoff, Connection, ConnectionEvents};
+use discovery::Identity;
+pub use error::NodeError;
+use events::DeviceEvents;
+use fade::{Fade, Fades};
+use lookup::Resolver;
+pub use mqtt::Mqtt;
+use output::Outputs;
+pub use query::NameMatching;
+use scenes::Scenes;
+pub use signing::Signing;
+use signing::Verifier;
+pub use state::PowerOn;
+use storage::Storage;
 use updaters::EncoderDevices;
+use wifi::NodeWifi;
+pub use wifi::{Credentials, StaticIp};
 //pub mod wrappers;
 //pub use encoder::{update_slider_type_device_from_encoder, Encoder, EncoderPeripheralData};
 
+/// Stack size of every thread the node starts
+pub(crate) const THREAD_STACK_SIZE: usize = 8 * 1024;
+
+/// Version of the HTTP API, advertised over mDNS and in discovery replies
+pub const API_VERSION: u32 = 1;
+
+/// NVS namespace the node keeps its own data in
+const NVS_NAMESPACE: &str = "node";
+
 pub struct Node {
+    /// What the node calls itself when it's advertised, see `service_type`
+    pub name: String,
+    /// The DNS-SD service type the node is advertised as, along with `_tcp`
+    pub service_type: String,
+    /// A network to join, tried before `networks`. Leave it and `networks`
+    /// empty to have the network picked through the provisioning portal.
     pub ssid: String,
     pub password: String,
+    /// More networks to join, in order of priority, see
+    /// `wifi::connect_any` for how one is picked
+    pub networks: Vec<Credentials>,
+    /// Fixed address settings for the network, DHCP is used if not given
+    pub static_ip: Option<StaticIp>,
+    /// The name the node gives itself on the network, and answers to as
+    /// `<hostname>.local`. If not given, it's made from `name`, e.g.
+    /// `living-room` for "Living Room".
+    pub hostname: Option<String>,
+    /// Name of the access point the provisioning portal is served on
+    pub ap_ssid: String,
+    /// How many times joining the network is tried before falling back to
+    /// the provisioning portal
+    pub connect_attempts: u32,
+    /// How long to wait between attempts to get back on the network after
+    /// dropping off it
+    pub backoff: Backoff,
+    /// Changes to the connection, e.g. for a status LED
+    pub connection_events: ConnectionEvents,
+    /// Port the `/events` stream is served on
+    pub events_port: u16,
+    /// Port the node answers `discovery::PROBE` broadcasts on
+    pub discovery_port: u16,
+    /// Device changes, can be subscribed to by the firmware as well
+    pub events: DeviceEvents,
+    /// Fades asked for with `duration_ms`, carried out by
+    /// `DevicesDutyCycles::update_duty_cycles_with`
+    pub fades: Fades,
+    /// How each device's level maps onto its driver's duty cycle, also
+    /// carried out by `DevicesDutyCycles::update_duty_cycles_with`
+    pub outputs: Outputs,
+    /// The physical inputs bound to each device, listed by `/capabilities`
+    pub inputs: HashMap<Uuid, Vec<Input>>,
+    /// Tokens needed to use the node, by default anyone on the network can
+    pub auth: Auth,
+    /// If set, commands have to be signed with its key, see `Signing`
+    pub signing: Option<Signing>,
+    /// If set, the devices are bridged to an MQTT broker, see `Mqtt`
+    pub mqtt: Option<Mqtt>,
+    /// How device names given in requests are compared to the devices'
+    pub name_matching: NameMatching,
+    /// Extra names devices can be found by, e.g. "lamp" for "Living Room Lamp"
+    pub aliases: HashMap<String, Uuid>,
+    /// Named sets of devices that `/command?group=` acts on together
+    pub groups: HashMap<String, Vec<Uuid>>,
+    /// What each device is set to when the node starts, devices that aren't
+    /// listed get `PowerOn::Restore`
+    pub power_on: HashMap<Uuid, PowerOn>,
+    /// How long the devices have to stay unchanged before their state is
+    /// saved, so that spinning an encoder doesn't wear out the flash
+    pub save_delay: Duration,
 }
 
 impl Default for Node {
     fn default() -> Self {
         Self {
+            name: "node".to_string(),
+            service_type: "_node".to_string(),
             ssid: String::default(),
             password: String::default(),
+            networks: Vec::new(),
+            static_ip: None,
+            hostname: None,
+            ap_ssid: "node-setup".to_string(),
+            connect_attempts: 5,
+            backoff: Backoff::default(),
+            connection_events: ConnectionEvents::default(),
+            events_port: 8081,
+            discovery_port: 8082,
+            events: DeviceEvents::default(),
+            fades: Fades::default(),
+            outputs: Outputs::default(),
+            inputs: HashMap::new(),
+            auth: Auth::default(),
+            signing: None,
+            mqtt: None,
+            name_matching: NameMatching::default(),
+            aliases: HashMap::new(),
+            groups: HashMap::new(),
+            power_on: HashMap::new(),
+            save_delay: Duration::from_secs(5),
         }
     }
 }
@@ -60,207 +187,161 @@ impl Node {
         Peripherals::take().expect("Something went wrong taking the Peripherals.")
     }
 
+    /// Finds devices the way requests to this node name them
+    pub(crate) fn resolver(&self) -> Resolver {
+        Resolver {
+            matching: self.name_matching,
+            aliases: self.aliases.clone(),
+            groups: self.groups.clone(),
+        }
+    }
+
+    /// Every network the node knows of, in order of priority: the one picked
+    /// through the provisioning portal, then `ssid`, then `networks`
+    fn known_networks(&self, storage: &Storage) -> Vec<Credentials> {
+        let mut networks: Vec<Credentials> = Credentials::load(storage).into_iter().collect();
+        if !self.ssid.is_empty() {
+            networks.push(Credentials {
+                ssid: self.ssid.clone(),
+                password: self.password.clone(),
+                bssid: None,
+            });
+        }
+        networks.extend(self.networks.iter().cloned());
+        networks
+    }
+
     //#[cfg(all(not(feature = "riscv-ulp-hal"), any(esp32, esp32s2, esp32s3)))]
-    pub fn run(&mut self, devices: Devices, modem: Modem) -> Result<(), EspIOError> {
-        let sys_loop = EspSystemEventLoop::take()?;
-        let nvs = EspDefaultNvsPartition::take()?;
-        let mut wifi_driver = EspWifi::new(modem, sys_loop, Some(nvs)).unwrap();
-        wifi_driver.set_configuration(&Configuration::Client(ClientConfiguration {
-            ssid: heapless::String::try_from(self.ssid.as_str()).unwrap(),
-            password: heapless::String::try_from(self.password.as_str()).unwrap(),
-            ..Default::default()
-        }))?;
-        wifi_driver.start().unwrap();
-        wifi_driver.connect().unwrap();
-        while !wifi_driver.is_connected().unwrap() {
-            //dbg!(wifi_driver.is_connected());
-            let config = wifi_driver.get_configuration().unwrap();
-            //println!("Waiting for station {:?}", config);
-            //dbg!(wifi_driver.is_connected());
-            sleep(Duration::new(10, 0)); // this is time in seconds
+    pub fn run(&mut self, devices: Devices, modem: Modem) -> Result<(), NodeError> {
+        let sys_loop = EspSystemEventLoop::take().map_err(NodeError::System)?;
+        let nvs = EspDefaultNvsPartition::take().map_err(NodeError::System)?;
+        let storage = Storage::new(nvs.clone(), NVS_NAMESPACE).map_err(NodeError::System)?;
+        // Before connecting, so the devices come back without waiting on wifi
+        state::restore(&devices, &storage, &self.power_on);
+        let changes = self.events.subscribe();
+        let state_storage = storage.clone();
+        let save_delay = self.save_delay;
+        thread::Builder::new()
+            .stack_size(THREAD_STACK_SIZE)
+            .spawn(move || state::persist(changes, state_storage, save_delay))
+            .map_err(NodeError::Spawn)?;
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => wifi::hostname_from(&self.name),
+        };
+        let mut wifi_driver =
+            wifi::create(modem, sys_loop, nvs, self.static_ip.as_ref(), &hostname)?;
+        let networks = self.known_networks(&storage);
+        if networks.is_empty() {
+            log::info!("No wifi credentials, starting the provisioning portal");
+            return provision::provision(&mut wifi_driver, &storage, &self.ap_ssid, &networks);
+        }
+        match wifi::connect_any(&mut wifi_driver, &networks, self.connect_attempts)? {
+            Some(joined) => log::info!("Joined {}", joined.ssid),
+            None => {
+                log::warn!("Couldn't join any network, starting the provisioning portal");
+                return provision::provision(&mut wifi_driver, &storage, &self.ap_ssid, &networks);
+            }
         }
         println!("Should be connected now");
 
-        let mut server = EspHttpServer::new(&SVC_Configuration::default()).unwrap();
+        let server_configuration = SVC_Configuration::default();
+        let mut server = EspHttpServer::new(&server_configuration).map_err(NodeError::Server)?;
+        #[cfg(feature = "mdns")]
+        let _mdns = match mdns::advertise(
+            &hostname,
+            &self.name,
+            &self.service_type,
+            server_configuration.http_port,
+            self.events_port,
+            devices.devices.lock().unwrap().len(),
+        ) {
+            Ok(mdns) => Some(mdns),
+            Err(e) => {
+                log::warn!("Couldn't advertise over mDNS: {}", e);
+                None
+            }
+        };
         /*for (path, method, handler) in handlers.iter() {
             server.fn_handler(path.as_str(), method, handler)
             .unwrap();
         }*/
-        /*server
-        .fn_handler("/", Method::Get, |request| {
-            let mut response = request.into_ok_response()?;
-            response.write_all("payload!!!!!".as_bytes())?;
-            Ok(())
-        })
-        .unwrap();*/
         dbg!("****************************** about to start server");
+        // Signed commands carry a unix time, so the clock has to be set
+        let _sntp = match self.signing {
+            Some(_) => Some(EspSntp::new_default().map_err(NodeError::System)?),
+            None => None,
+        };
+        let verifier = self.signing.clone().map(Verifier::new);
+        let scenes = Scenes::load(storage);
+        server::register_handlers(&mut server, &devices, self, &verifier, &scenes)
+            .map_err(NodeError::Server)?;
+        #[cfg(feature = "websocket")]
+        ws::register(
+            &mut server,
+            &devices,
+            &self.events,
+            &self.fades,
+            &self.auth,
+            &verifier,
+            &self.resolver(),
+        )?;
+
+        let events = self.events.clone();
         let devices_clone = devices.clone();
-        server
-            .fn_handler("/status", Method::Get, move |request| {
-                if &request.uri().len() < &8_usize {
-                    let _ = exit_early(request, "Bad Status command given", 422);
-                    return Ok(());
-                    //return Ok::<(), EspIOError>(());
-                }
-                let query = &request.uri()[8..].to_string().to_lowercase();
-                let query: HashMap<_, _> = querystring::querify(query).into_iter().collect();
-                if query.get("device").is_some() {
-                    let d = query.get("device").unwrap();
-                    let d = d.replace("%20", " ");
-                    for device in devices_clone.devices.lock().unwrap().iter() {
-                        if device.name == d {
-                            let mut response = request.into_ok_response()?;
-                            let _ = response.write_all(&device.to_json().into_bytes()[..]);
-                            //return Ok(());
-                            return Ok::<(), EspIOError>(());
-                        }
-                    }
-                    let _ = exit_early(request, "Device name not found", 422);
-                    return Ok(());
-                } else if query.get("uuid").is_some() {
-                    let u = query.get("uuid").unwrap();
-                    for device in devices_clone.devices.lock().unwrap().iter() {
-                        if &device.uuid.to_string().as_str() == u {
-                            let mut response = request.into_ok_response()?;
-                            let _ = response.write_all(&device.to_json().into_bytes()[..]);
-                            //return Ok(());
-                            return Ok::<(), EspIOError>(());
-                        }
-                    }
-                    let _ = exit_early(request, "Device name not found", 422);
-                    //return Ok(());
-                    return Ok::<(), EspIOError>(());
-                } else {
-                    let _ = exit_early(request, "No Device name given", 422);
-                    //return Ok(());
-                    return Ok::<(), EspIOError>(());
+        thread::Builder::new()
+            .stack_size(THREAD_STACK_SIZE)
+            .spawn(move || events.watch(devices_clone, Duration::from_millis(100)))
+            .map_err(NodeError::Spawn)?;
+        let events = self.events.clone();
+        let auth = self.auth.clone();
+        let events_port = self.events_port;
+        thread::Builder::new()
+            .stack_size(THREAD_STACK_SIZE)
+            .spawn(move || {
+                if let Err(e) = events::serve_events(events, auth, events_port) {
+                    log::error!("Couldn't serve events: {}", e);
                 }
             })
-            .unwrap();
+            .map_err(NodeError::Spawn)?;
         let devices_clone = devices.clone();
-        server
-            .fn_handler("/devices", Method::Get, move |request| {
-                let mut devices = HashMap::new();
+        let identity = Identity {
+            name: self.name.clone(),
+            hostname,
+            http_port: server_configuration.http_port,
+            events_port: self.events_port,
+        };
+        let discovery_port = self.discovery_port;
+        let auth = self.auth.clone();
+        thread::Builder::new()
+            .stack_size(THREAD_STACK_SIZE)
+            .spawn(move || {
+                if let Err(e) =
+                    discovery::serve_discovery(devices_clone, identity, auth, discovery_port)
                 {
-                    for device in devices_clone.devices.lock().unwrap().iter() {
-                        devices.insert(device.name.clone(), device.clone());
-                    }
+                    log::error!("Couldn't answer discovery probes: {}", e);
                 }
-                let payload = serde_json::json!(devices);
-                let mut response = request.into_ok_response()?;
-                response.write_all(payload.to_string().as_bytes())?;
-                //Ok(())
-                return Ok::<(), EspIOError>(());
             })
-            .unwrap();
-        let devices_clone = devices.clone();
-        server
-            .fn_handler("/command", Method::Get, move |request| {
-                if &request.uri().len() < &9_usize {
-                    let _ = exit_early(request, "Bad Command given", 422);
-                    //return Ok(());
-                    return Ok::<(), EspIOError>(());
-                }
-                let query = &request.uri()[9..].to_string();
-                let query: HashMap<_, _> = querystring::querify(query).into_iter().collect();
-                let target: Option<usize> = match query.get("target") {
-                    Some(t_text) => match t_text.parse::<usize>() {
-                        Ok(t_num) => {
-                            if t_num > 7 {
-                                let _ = exit_early(request, "Target must be >= 0 & < 8", 422);
-                                //return Ok(());
-                                return Ok::<(), EspIOError>(());
-                            }
-                            Some(t_num)
-                        }
-                        Err(_) => {
-                            if t_text != &"" {
-                                let _ = exit_early(request, "Bad Target given", 422);
-                                //return Ok(());
-                                return Ok::<(), EspIOError>(());
-                            }
-                            None
-                        }
-                    },
-                    None => None,
-                };
-                let action = match query.get("action") {
-                    Some(a) => match Action::from_str(&a.to_lowercase(), target) {
-                        Ok(a) => a,
-                        Err(_) => {
-                            let _ = exit_early(request, "Bad Action name given", 422);
-                            //return Ok(());
-                            return Ok::<(), EspIOError>(());
-                        }
-                    },
-                    None => {
-                        let _ = exit_early(request, "No Action given", 422);
-                        //return Ok(());
-                        return Ok::<(), EspIOError>(());
-                    }
-                };
-                match query.get("uuid") {
-                    Some(u) => match Uuid::parse_str(u) {
-                        Ok(uuid) => {
-                            for device in devices_clone.devices.lock().unwrap().iter_mut() {
-                                if uuid == device.uuid {
-                                    let _ = device.take_action(action);
-                                    let mut response = request.into_ok_response()?;
-                                    let _ = response.write_all(&device.to_json().into_bytes());
-                                    //return Ok(());
-                                    return Ok::<(), EspIOError>(());
-                                }
-                            }
-                            let _ = exit_early(request, "Uuid not found among devices", 422);
-                            //`return Ok(());
-                            return Ok::<(), EspIOError>(());
-                        }
-                        Err(_) => {
-                            let _ = exit_early(request, "Bad Uuid given", 422);
-                            //return Ok(());
-                            return Ok::<(), EspIOError>(());
-                        }
-                    },
-                    None => {
-                        let _ = exit_early(request, "Uuid field not given", 422);
-                        //return Ok(());
-                        return Ok::<(), EspIOError>(());
-                    }
-                };
-            })
-            .unwrap();
-
-        let mut count = 0;
-        loop {
-            if count % 100 == 0 {
-                println!(
-                    "IP info: {:?}",
-                    wifi_driver.sta_netif().get_ip_info().unwrap()
-                );
-            }
-            count = count + 1;
-            match wifi_driver.is_connected() {
-                Ok(connected) => {
-                    if !connected {
-                        wifi_driver.disconnect().unwrap();
-                        wifi_driver.connect().unwrap();
-                        let mut count = 0;
-                        while !wifi_driver.is_connected().unwrap() && count < 10 {
-                            count = count + 1;
-                            println!("Trying to connect... {}", count);
-                            let config = wifi_driver.get_configuration().unwrap();
-                            sleep(Duration::new(1, 0));
-                        }
-                    } else {
-                        println!("Connected!");
-                    }
-                }
-                Err(_) => {
-                    println!("is_connected error!!!");
-                }
-            }
-            sleep(Duration::new(10, 0));
+            .map_err(NodeError::Spawn)?;
+        if let Some(settings) = &self.mqtt {
+            let bridge = mqtt::Bridge {
+                devices: devices.clone(),
+                events: self.events.clone(),
+                fades: self.fades.clone(),
+                auth: self.auth.clone(),
+                verifier: verifier.clone(),
+                resolver: self.resolver(),
+            };
+            mqtt::start(settings, &self.name, bridge)?;
         }
+
+        let mut connection = Connection::new(
+            NodeWifi::new(wifi_driver, networks),
+            self.backoff,
+            wifi::CONNECT_TIMEOUT,
+            self.connection_events.clone(),
+        );
+        connection.run()
         //Ok(())
     }
 }
@@ -283,16 +364,6 @@ pub fn get_max_duty_cycles(drivers: &Vec<LedcDriver>) -> Vec<u32> {
     max_duty_cycles
 }
 
-fn exit_early<'a>(
-    request: Request<&mut EspHttpConnection<'a>>,
-    message: &str,
-    code: u16,
-) -> Result<(), EspIOError> {
-    let mut response = request.into_status_response(code)?;
-    let _ = response.write_all(message.as_bytes());
-    Ok(())
-}
-
 pub trait DevicesDutyCycles {
     fn update_duty_cycles(
         &mut self,
@@ -300,6 +371,24 @@ pub trait DevicesDutyCycles {
         max_duty_cycles: Vec<u32>,
         delay_ms: u32,
     );
+
+    /// Like `update_duty_cycles`, but ramps a driver to its new duty cycle
+    /// when the command that changed its device asked for a fade, and maps
+    /// each duty cycle through the device's `OutputConfig`
+    ///
+    /// Pass in `Node::fades` and `Node::outputs`. Until this is running,
+    /// commands that ask for a fade are turned away. A command arriving
+    /// during a fade starts from wherever the fade had got to. `delay_ms` is
+    /// how often the duty cycles are stepped, so keep it to 20 or so for
+    /// fades to look smooth.
+    fn update_duty_cycles_with(
+        &mut self,
+        drivers: Vec<LedcDriver>,
+        max_duty_cycles: Vec<u32>,
+        delay_ms: u32,
+        fades: Fades,
+        outputs: Outputs,
+    );
 }
 
 impl DevicesDutyCycles for Devices {
@@ -330,6 +419,61 @@ impl DevicesDutyCycles for Devices {
             delay.delay_ms(delay_ms);
         }
     }
+
+    fn update_duty_cycles_with(
+        &mut self,
+        mut drivers: Vec<LedcDriver>,
+        max_duty_cycles: Vec<u32>,
+        delay_ms: u32,
+        fades: Fades,
+        outputs: Outputs,
+    ) {
+        fades.start();
+        let delay = Delay::new(100);
+        // What the devices asked for, before `outputs` maps them, which is
+        // what fades move through so that they follow the dimming curve
+        let mut duty_cycles: Vec<u32> = vec![0; drivers.len()];
+        let mut running: Vec<Option<Fade>> = vec![None; drivers.len()];
+        loop {
+            {
+                let now = Instant::now();
+                for ((((device, driver), max_duty), duty_cycle), fade) in self
+                    .devices
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .zip(drivers.iter_mut())
+                    .zip(max_duty_cycles.iter())
+                    .zip(duty_cycles.iter_mut())
+                    .zip(running.iter_mut())
+                {
+                    // Taken every time, so a fade can't be left over for a
+                    // later change when its command didn't change anything
+                    let requested = fades.take(&device.uuid);
+                    if device.needs_hardware_duty_cycle_update() {
+                        let target = device.get_and_update_duty_cycle(max_duty);
+                        *fade =
+                            requested.map(|duration| Fade::new(*duty_cycle, target, now, duration));
+                        if fade.is_none() {
+                            let _ = driver.set_duty(outputs.duty(&device.uuid, target, *max_duty));
+                            *duty_cycle = target;
+                        }
+                    }
+                    if let Some(running_fade) = *fade {
+                        let next = running_fade.duty_at(now);
+                        if next != *duty_cycle {
+                            let _ = driver.set_duty(outputs.duty(&device.uuid, next, *max_duty));
+                            *duty_cycle = next;
+                        }
+                        if running_fade.is_done(now) {
+                            *fade = None;
+                        }
+                    }
+                }
+            }
+            delay.delay_ms(delay_ms);
+        }
+    }
 }
 /*
 pub fn update_device_duty_cycles(
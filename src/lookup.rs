@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use device::Device;
+
+use crate::error::ApiError;
+use crate::query::NameMatching;
+
+/// How a request picks out a device, or a group of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Uuid(Uuid),
+    /// A device's name or one of its aliases
+    Name(String),
+    /// The name of a group, see `Resolver::groups`
+    Group(String),
+}
+
+impl DeviceSelector {
+    /// Picks the uuid, then the name, then the group, whichever is given first
+    pub fn parse(
+        uuid: Option<&str>,
+        name: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        match (uuid, name, group) {
+            (Some(u), _, _) => Uuid::parse_str(u)
+                .map(DeviceSelector::Uuid)
+                .map_err(|_| ApiError::InvalidField("uuid")),
+            (None, Some(name), _) => Ok(DeviceSelector::Name(name.to_string())),
+            (None, None, Some(group)) => Ok(DeviceSelector::Group(group.to_string())),
+            (None, None, None) => Err(ApiError::MissingField("uuid")),
+        }
+    }
+}
+
+/// Finds the device a `DeviceSelector` is talking about
+///
+/// Names are compared using `matching`, both against the devices' names and
+/// against `aliases`, which gives devices extra names, e.g. "lamp" for
+/// "Living Room Floor Lamp". `groups` names sets of devices, e.g. "kitchen".
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    pub matching: NameMatching,
+    pub aliases: HashMap<String, Uuid>,
+    pub groups: HashMap<String, Vec<Uuid>>,
+}
+
+impl Resolver {
+    /// The index of the device within `devices`
+    ///
+    /// Fails if nothing matches, if a name matches more than one device, or
+    /// if the selector is a group
+    pub fn resolve(
+        &self,
+        devices: &[Device],
+        selector: &DeviceSelector,
+    ) -> Result<usize, ApiError> {
+        match selector {
+            DeviceSelector::Uuid(uuid) => devices
+                .iter()
+                .position(|device| device.uuid == *uuid)
+                .ok_or(ApiError::UnknownUuid),
+            DeviceSelector::Name(name) => {
+                let aliased: Vec<&Uuid> = self
+                    .aliases
+                    .iter()
+                    .filter(|(alias, _)| self.matching.matches(alias, name))
+                    .map(|(_, uuid)| uuid)
+                    .collect();
+                let found: Vec<usize> = devices
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, device)| {
+                        self.matching.matches(&device.name, name) || aliased.contains(&&device.uuid)
+                    })
+                    .map(|(index, _)| index)
+                    .collect();
+                match found.as_slice() {
+                    [] => Err(ApiError::UnknownDevice),
+                    [index] => Ok(*index),
+                    _ => Err(ApiError::AmbiguousDevice),
+                }
+            }
+            DeviceSelector::Group(_) => Err(ApiError::InvalidField("group")),
+        }
+    }
+
+    /// The uuids of the devices in the group
+    pub fn group(&self, name: &str) -> Result<&[Uuid], ApiError> {
+        self.groups
+            .iter()
+            .find(|(group, _)| self.matching.matches(group, name))
+            .map(|(_, members)| members.as_slice())
+            .ok_or(ApiError::UnknownGroup)
+    }
+}
@@ -0,0 +1,42 @@
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_sys::EspError;
+
+use crate::API_VERSION;
+
+/// Advertises the node as `<service_type>._tcp` on `port` and answers for
+/// `<hostname>.local`
+///
+/// The TXT records carry the node's name, the API version and how many
+/// devices it has, so a controller can tell nodes apart before asking any of
+/// them for `/devices`. Advertising stops once the returned `EspMdns` is
+/// dropped.
+///
+/// Needs the `espressif/mdns` component in the firmware.
+pub fn advertise(
+    hostname: &str,
+    name: &str,
+    service_type: &str,
+    port: u16,
+    events_port: u16,
+    device_count: usize,
+) -> Result<EspMdns, EspError> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(name)?;
+    let api_version = API_VERSION.to_string();
+    let device_count = device_count.to_string();
+    let events_port = events_port.to_string();
+    mdns.add_service(
+        Some(name),
+        service_type,
+        "_tcp",
+        port,
+        &[
+            ("name", name),
+            ("api", api_version.as_str()),
+            ("devices", device_count.as_str()),
+            ("events_port", events_port.as_str()),
+        ],
+    )?;
+    Ok(mdns)
+}
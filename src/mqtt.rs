@@ -0,0 +1,264 @@
+use std::{
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EspMqttConnection, EventPayload, MqttClientConfiguration, QoS,
+};
+use esp_idf_sys::EspError;
+use uuid::Uuid;
+
+use device::Devices;
+
+use crate::auth::{Access, Auth};
+use crate::command::{self, CommandRequest};
+use crate::error::{ApiError, NodeError};
+use crate::events::{DeviceChange, DeviceEvents};
+use crate::fade::Fades;
+use crate::lookup::Resolver;
+use crate::signing::Verifier;
+use crate::THREAD_STACK_SIZE;
+
+/// Settings for bridging the devices to an MQTT broker
+///
+/// Each device's json is published, retained, to `node/<node>/<uuid>/state`
+/// whenever it changes, and commands are taken from
+/// `node/<node>/<uuid>/set`, where `<node>` is `Node::name`. A command is the
+/// same JSON as the body of `POST /command`, aimed at the device in the
+/// topic, e.g. `{"action": "set", "target": 5}`. A `"uuid"` in the
+/// command has to match the topic's, and `"device"` and `"group"` can't be
+/// given. When commands are signed, the `"uuid"` has to be in the command, so
+/// that a signature can't be moved onto another device's topic. Commands
+/// that fail are answered on `node/<node>/<uuid>/error`.
+#[derive(Debug, Clone)]
+pub struct Mqtt {
+    /// The broker, e.g. `mqtt://192.168.1.10:1883`
+    pub url: String,
+    /// `Node::name` if not given
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Mqtt {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client_id: None,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// Work for the thread that owns the client
+///
+/// The client can't be used while an event from its connection is being
+/// looked at, so everything that publishes or subscribes is handed over.
+enum Job {
+    Connected,
+    Command { topic: String, payload: Vec<u8> },
+    Change(DeviceChange),
+}
+
+/// What the bridge needs to carry out commands the way `/ws` does
+pub struct Bridge {
+    pub devices: Devices,
+    pub events: DeviceEvents,
+    pub fades: Fades,
+    pub auth: Auth,
+    pub verifier: Option<Verifier>,
+    pub resolver: Resolver,
+}
+
+/// Connects to the broker and starts bridging, reconnecting by itself
+/// whenever the broker drops
+pub fn start(settings: &Mqtt, name: &str, bridge: Bridge) -> Result<(), NodeError> {
+    let client_id = settings.client_id.as_deref().unwrap_or(name);
+    let configuration = MqttClientConfiguration {
+        client_id: Some(client_id),
+        username: settings.username.as_deref(),
+        password: settings.password.as_deref(),
+        ..Default::default()
+    };
+    let (client, connection) =
+        EspMqttClient::new(&settings.url, &configuration).map_err(NodeError::Mqtt)?;
+    let (jobs, next_job) = channel();
+
+    let connection_jobs = jobs.clone();
+    thread::Builder::new()
+        .stack_size(THREAD_STACK_SIZE)
+        .spawn(move || receive(connection, connection_jobs))
+        .map_err(NodeError::Spawn)?;
+
+    let changes = bridge.events.subscribe();
+    thread::Builder::new()
+        .stack_size(THREAD_STACK_SIZE)
+        .spawn(move || {
+            for change in changes.iter() {
+                if jobs.send(Job::Change(change)).is_err() {
+                    return;
+                }
+            }
+        })
+        .map_err(NodeError::Spawn)?;
+
+    let mut worker = Worker {
+        client,
+        prefix: format!("node/{}", name),
+        bridge,
+    };
+    thread::Builder::new()
+        .stack_size(THREAD_STACK_SIZE)
+        .spawn(move || {
+            for job in next_job.iter() {
+                worker.handle(job);
+            }
+        })
+        .map_err(NodeError::Spawn)?;
+    Ok(())
+}
+
+/// Turns the connection's events into `Job`s until the client is dropped
+fn receive(mut connection: EspMqttConnection, jobs: Sender<Job>) {
+    while let Ok(event) = connection.next() {
+        let job = match event.payload() {
+            EventPayload::Connected(_) => Job::Connected,
+            EventPayload::Disconnected => {
+                log::warn!("Lost the MQTT broker, reconnecting");
+                continue;
+            }
+            // Commands are small, so one split over several events is
+            // dropped rather than put back together
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                details: Details::Complete,
+                ..
+            } => Job::Command {
+                topic: topic.to_string(),
+                payload: data.to_vec(),
+            },
+            EventPayload::Error(e) => {
+                log::warn!("MQTT error: {:?}", e);
+                continue;
+            }
+            _ => continue,
+        };
+        if jobs.send(job).is_err() {
+            return;
+        }
+    }
+}
+
+struct Worker {
+    client: EspMqttClient<'static>,
+    /// `node/<node>`, which every topic starts with
+    prefix: String,
+    bridge: Bridge,
+}
+
+impl Worker {
+    fn handle(&mut self, job: Job) {
+        let result = match job {
+            Job::Connected => self.on_connect(),
+            Job::Command { topic, payload } => self.on_command(&topic, &payload),
+            Job::Change(change) => self.publish_state(&change.uuid, &change.json),
+        };
+        if let Err(e) = result {
+            log::warn!("Couldn't reach the MQTT broker: {}", e);
+        }
+    }
+
+    /// Subscribes to commands and publishes every device's state, as changes
+    /// made while the broker was away were never sent
+    fn on_connect(&mut self) -> Result<(), EspError> {
+        log::info!("Connected to the MQTT broker");
+        self.client
+            .subscribe(&format!("{}/+/set", self.prefix), QoS::AtLeastOnce)?;
+        let states: Vec<(Uuid, String)> = self
+            .bridge
+            .devices
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|device| (device.uuid, device.to_json()))
+            .collect();
+        for (uuid, json) in states {
+            self.publish_state(&uuid, &json)?;
+        }
+        Ok(())
+    }
+
+    fn on_command(&mut self, topic: &str, payload: &[u8]) -> Result<(), EspError> {
+        let uuid = match self.command_uuid(topic) {
+            Some(uuid) => uuid,
+            None => return Ok(()),
+        };
+        match self.apply(uuid, payload) {
+            // The new state is published once the watcher sees the change
+            Ok(()) => {
+                self.bridge.events.notify();
+                Ok(())
+            }
+            Err(error) => {
+                let topic = format!("{}/{}/error", self.prefix, uuid);
+                self.client
+                    .publish(&topic, QoS::AtMostOnce, false, error.to_json().as_bytes())
+                    .map(|_| ())
+            }
+        }
+    }
+
+    /// The uuid in a `<prefix>/<uuid>/set` topic
+    fn command_uuid(&self, topic: &str) -> Option<Uuid> {
+        let uuid = topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?;
+        Uuid::parse_str(uuid).ok()
+    }
+
+    fn apply(&self, uuid: Uuid, payload: &[u8]) -> Result<(), ApiError> {
+        let mut command: CommandRequest =
+            serde_json::from_slice(payload).map_err(|_| ApiError::InvalidJson)?;
+        if command.device.is_some() {
+            return Err(ApiError::InvalidField("device"));
+        }
+        if command.group.is_some() {
+            return Err(ApiError::InvalidField("group"));
+        }
+        match command.uuid.as_deref().map(Uuid::parse_str) {
+            Some(Ok(given)) if given == uuid => {}
+            Some(_) => return Err(ApiError::InvalidField("uuid")),
+            None if self.bridge.verifier.is_some() => return Err(ApiError::MissingField("uuid")),
+            None => command.uuid = Some(uuid.to_string()),
+        }
+        let bridge = &self.bridge;
+        bridge
+            .auth
+            .check_token(Access::Write, command.token.as_deref())?;
+        if let Some(verifier) = &bridge.verifier {
+            verifier.verify(&command.params())?;
+        }
+        let (selector, action, fade) = command.validate()?;
+        command::apply(
+            &bridge.devices,
+            &bridge.resolver,
+            &bridge.fades,
+            &selector,
+            action,
+            fade,
+        )
+        .map(|_| ())
+    }
+
+    fn publish_state(&mut self, uuid: &Uuid, json: &str) -> Result<(), EspError> {
+        let topic = format!("{}/{}/state", self.prefix, uuid);
+        self.client
+            .publish(&topic, QoS::AtLeastOnce, true, json.as_bytes())
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::command::MAX_TARGET;
+use crate::error::ApiError;
+
+/// Most points a `DimmingCurve::Table` can have
+pub const MAX_TABLE_POINTS: usize = 64;
+
+/// How a device's level, as a fraction of full, is turned into a fraction of
+/// the driver's full duty cycle
+///
+/// LEDs look much brighter at low duty cycles than a linear mapping would
+/// suggest, so the perceptual curves spend more of the range down there.
+/// Sent and received as JSON tagged by `"kind"`, e.g.
+/// `{"kind": "gamma", "gamma": 2.2}`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DimmingCurve {
+    #[default]
+    Linear,
+    /// `level ^ gamma`, 2.2 is typical
+    Gamma { gamma: f32 },
+    /// CIE 1931 lightness, so each step looks the same size
+    Cie1931,
+    /// Evenly spaced points from no level to full, each between 0 and 1,
+    /// with straight lines between them
+    Table { points: Vec<f32> },
+}
+
+impl DimmingCurve {
+    pub fn gamma() -> Self {
+        DimmingCurve::Gamma { gamma: 2.2 }
+    }
+
+    /// Fails on curves that can't be used, e.g. a table with a single point
+    pub fn check(&self) -> Result<(), ApiError> {
+        let usable = match self {
+            DimmingCurve::Linear | DimmingCurve::Cie1931 => true,
+            DimmingCurve::Gamma { gamma } => gamma.is_finite() && *gamma > 0.0,
+            DimmingCurve::Table { points } => {
+                (2..=MAX_TABLE_POINTS).contains(&points.len())
+                    && points.iter().all(|point| (0.0..=1.0).contains(point))
+            }
+        };
+        match usable {
+            true => Ok(()),
+            false => Err(ApiError::InvalidField("curve")),
+        }
+    }
+
+    /// Maps `level`, between 0 and 1, onto the curve
+    pub fn apply(&self, level: f32) -> f32 {
+        let level = level.clamp(0.0, 1.0);
+        match self {
+            DimmingCurve::Linear => level,
+            DimmingCurve::Gamma { gamma } => level.powf(*gamma),
+            DimmingCurve::Cie1931 => {
+                let lightness = level * 100.0;
+                if lightness <= 8.0 {
+                    lightness / 903.3
+                } else {
+                    ((lightness + 16.0) / 116.0).powi(3)
+                }
+            }
+            // `check` keeps these out of `Outputs`, but a curve built by hand
+            // could still be applied
+            DimmingCurve::Table { points } if points.len() < 2 => level,
+            DimmingCurve::Table { points } => {
+                let position = level * (points.len() - 1) as f32;
+                let index = (position as usize).min(points.len() - 2);
+                let fraction = position - index as f32;
+                points[index] + (points[index + 1] - points[index]) * fraction
+            }
+        }
+    }
+}
+
+/// How a device's level is turned into its driver's duty cycle
+///
+/// The duties are fractions of the driver's full duty cycle. Off is always
+/// a duty cycle of 0, whatever the calibration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputConfig {
+    #[serde(default)]
+    pub curve: DimmingCurve,
+    /// The duty cycle of the lowest level, for drivers that flicker or
+    /// stall below it. 0 means the levels start from nothing.
+    #[serde(default)]
+    pub min_duty: f32,
+    /// The duty cycle of the highest level
+    #[serde(default = "full_duty")]
+    pub max_duty: f32,
+    /// Levels, as fractions of full, at or below this are off, so that a
+    /// fade to off doesn't sit at `min_duty` on its way down
+    #[serde(default)]
+    pub off_threshold: f32,
+}
+
+fn full_duty() -> f32 {
+    1.0
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            curve: DimmingCurve::default(),
+            min_duty: 0.0,
+            max_duty: full_duty(),
+            off_threshold: 0.0,
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Fails on settings that can't be used, e.g. a minimum above the maximum
+    pub fn check(&self) -> Result<(), ApiError> {
+        self.curve.check()?;
+        if !(0.0..=1.0).contains(&self.max_duty) {
+            return Err(ApiError::InvalidField("max_duty"));
+        }
+        if !(0.0..self.max_duty).contains(&self.min_duty) {
+            return Err(ApiError::InvalidField("min_duty"));
+        }
+        if !(0.0..1.0).contains(&self.off_threshold) {
+            return Err(ApiError::InvalidField("off_threshold"));
+        }
+        Ok(())
+    }
+
+    /// The duty cycle to give the driver for `duty`, the duty cycle the
+    /// device asked for against `max_duty`
+    ///
+    /// With a `min_duty`, the levels are stretched so that the lowest one,
+    /// a target of 1, comes out at `min_duty` rather than above it.
+    pub fn duty(&self, duty: u32, max_duty: u32) -> u32 {
+        if max_duty == 0 {
+            return 0;
+        }
+        let level = duty as f32 / max_duty as f32;
+        if duty == 0 || level <= self.off_threshold {
+            return 0;
+        }
+        let level = match self.min_duty > 0.0 {
+            true => {
+                let lowest = 1.0 / MAX_TARGET as f32;
+                (level - lowest) / (1.0 - lowest)
+            }
+            false => level,
+        };
+        let range = self.max_duty - self.min_duty;
+        let fraction = (self.min_duty + self.curve.apply(level) * range).clamp(0.0, 1.0);
+        (fraction * max_duty as f32).round() as u32
+    }
+}
+
+/// The JSON body accepted by `POST /output`, e.g.
+/// `{"device": "Desk Lamp", "curve": {"kind": "cie1931"}, "min_duty": 0.08}`
+///
+/// Only the settings that are given are changed.
+#[derive(Debug, Deserialize)]
+pub struct OutputRequest {
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub curve: Option<DimmingCurve>,
+    #[serde(default)]
+    pub min_duty: Option<f32>,
+    #[serde(default)]
+    pub max_duty: Option<f32>,
+    #[serde(default)]
+    pub off_threshold: Option<f32>,
+}
+
+impl OutputRequest {
+    /// Changes the given settings of `config`
+    pub fn update(&self, config: &mut OutputConfig) {
+        if let Some(curve) = &self.curve {
+            config.curve = curve.clone();
+        }
+        if let Some(min_duty) = self.min_duty {
+            config.min_duty = min_duty;
+        }
+        if let Some(max_duty) = self.max_duty {
+            config.max_duty = max_duty;
+        }
+        if let Some(off_threshold) = self.off_threshold {
+            config.off_threshold = off_threshold;
+        }
+    }
+}
+
+/// Each device's `OutputConfig`, shared between the API and the duty cycle
+/// loop, see `DevicesDutyCycles::update_duty_cycles_with`
+///
+/// Devices that were never configured use `OutputConfig::default()`.
+/// Changes made through the API last until the node restarts, so settings
+/// that should stick are best made at setup.
+#[derive(Debug, Clone, Default)]
+pub struct Outputs {
+    configs: Arc<Mutex<HashMap<Uuid, OutputConfig>>>,
+}
+
+impl Outputs {
+    /// Fails, leaving the device as it was, on a config `check` turns away
+    pub fn set(&self, uuid: Uuid, config: OutputConfig) -> Result<(), ApiError> {
+        config.check()?;
+        self.configs.lock().unwrap().insert(uuid, config);
+        Ok(())
+    }
+
+    /// Fails, leaving the device as it was, on a curve `check` turns away
+    pub fn set_curve(&self, uuid: Uuid, curve: DimmingCurve) -> Result<(), ApiError> {
+        curve.check()?;
+        self.configs.lock().unwrap().entry(uuid).or_default().curve = curve;
+        Ok(())
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> OutputConfig {
+        self.configs
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Applies `request` to the device's config, unless that would leave it
+    /// unusable
+    pub fn update(&self, uuid: Uuid, request: &OutputRequest) -> Result<(), ApiError> {
+        let mut configs = self.configs.lock().unwrap();
+        let mut config = configs.get(&uuid).cloned().unwrap_or_default();
+        request.update(&mut config);
+        config.check()?;
+        configs.insert(uuid, config);
+        Ok(())
+    }
+
+    /// See `OutputConfig::duty`
+    pub fn duty(&self, uuid: &Uuid, duty: u32, max_duty: u32) -> u32 {
+        match self.configs.lock().unwrap().get(uuid) {
+            Some(config) => config.duty(duty, max_duty),
+            None => OutputConfig::default().duty(duty, max_duty),
+        }
+    }
+
+    pub fn to_value(&self, uuid: &Uuid) -> Value {
+        json!({ "uuid": uuid, "output": self.get(uuid) })
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    io,
+    net::{Ipv4Addr, UdpSocket},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use embedded_svc::{http::Method, io::Write};
+use esp_idf_hal::reset::restart;
+use esp_idf_svc::http::server::{Configuration as SVC_Configuration, EspHttpServer};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration, Configuration,
+    EspWifi,
+};
+use esp_idf_sys::EspError;
+use serde_json::json;
+
+use crate::error::{ApiError, NodeError};
+use crate::query::Query;
+use crate::server::{exit_early, read_body};
+use crate::storage::Storage;
+use crate::wifi::{self, Credentials, CONNECT_TIMEOUT};
+use crate::THREAD_STACK_SIZE;
+
+/// Setup page served by the provisioning portal
+const PORTAL: &str = include_str!("provision.html");
+/// How often the networks the node already knows are looked for while the
+/// portal is up
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs the provisioning portal until someone picks a network, then saves
+/// it and restarts the node so that it joins it
+///
+/// The node starts an open access point named `ap_ssid`. Every name looked
+/// up through it resolves to the node and every unknown page redirects to
+/// the setup page, so phones and laptops pop the page up as soon as they
+/// join.
+///
+/// Meanwhile, `known` networks are looked for every `RETRY_INTERVAL`, and if
+/// one of them can be joined the node restarts too, so that a node which
+/// came up before its router doesn't wait in the portal for good. Only
+/// returns if the portal couldn't be started.
+pub fn provision(
+    wifi: &mut EspWifi<'static>,
+    storage: &Storage,
+    ap_ssid: &str,
+    known: &[Credentials],
+) -> Result<(), NodeError> {
+    let ap = AccessPointConfiguration {
+        ssid: heapless::String::try_from(ap_ssid)
+            .map_err(|_| NodeError::Config("access point ssid is longer than 32 bytes"))?,
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+    let _ = wifi.stop();
+    // Mixed, since networks can only be scanned for as a client
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        ap.clone(),
+    ))
+    .map_err(NodeError::Wifi)?;
+    wifi.start().map_err(NodeError::Wifi)?;
+    let networks = match wifi.scan() {
+        Ok(networks) => networks,
+        Err(e) => {
+            log::warn!("Couldn't scan for networks: {}", e);
+            Vec::new()
+        }
+    };
+    let ip = Ipv4Addr::from(
+        wifi.ap_netif()
+            .get_ip_info()
+            .map_err(NodeError::Wifi)?
+            .ip
+            .octets(),
+    );
+    log::info!("Provisioning through {} at http://{}/", ap_ssid, ip);
+
+    thread::Builder::new()
+        .stack_size(THREAD_STACK_SIZE)
+        .spawn(move || {
+            if let Err(e) = serve_dns(ip) {
+                log::error!("Couldn't serve DNS: {}", e);
+            }
+        })
+        .map_err(NodeError::Spawn)?;
+
+    let (saved, wait_for_save) = channel();
+    let mut server = EspHttpServer::new(&SVC_Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+    .map_err(NodeError::Server)?;
+    register_portal(&mut server, &networks, storage, saved, ip).map_err(NodeError::Server)?;
+
+    loop {
+        match wait_for_save.recv_timeout(RETRY_INTERVAL) {
+            Ok(()) => {
+                // Gives the response time to get out before the access point
+                // goes
+                sleep(Duration::from_secs(2));
+                restart();
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if join_known(wifi, known, &ap)? {
+                    log::info!("A known network is back, restarting to join it");
+                    restart();
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Tries each of the `known` networks a scan finds, keeping the access point
+/// up, and returns whether one was joined
+fn join_known(
+    wifi: &mut EspWifi<'static>,
+    known: &[Credentials],
+    ap: &AccessPointConfiguration,
+) -> Result<bool, NodeError> {
+    if known.is_empty() {
+        return Ok(false);
+    }
+    let scanned = match wifi.scan() {
+        Ok(scanned) => scanned,
+        Err(e) => {
+            log::warn!("Couldn't scan for networks: {}", e);
+            return Ok(false);
+        }
+    };
+    for credentials in wifi::by_preference(known, &scanned) {
+        if !scanned.iter().any(|found| credentials.matches(found)) {
+            continue;
+        }
+        wifi.set_configuration(&Configuration::Mixed(
+            credentials.client_configuration()?,
+            ap.clone(),
+        ))
+        .map_err(NodeError::Wifi)?;
+        if let Err(e) = wifi.connect() {
+            log::warn!("Couldn't connect to {}: {}", credentials.ssid, e);
+            continue;
+        }
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        while Instant::now() < deadline {
+            if wifi.is_connected().map_err(NodeError::Wifi)? {
+                return Ok(true);
+            }
+            sleep(Duration::from_millis(500));
+        }
+        let _ = wifi.disconnect();
+    }
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        ap.clone(),
+    ))
+    .map_err(NodeError::Wifi)?;
+    Ok(false)
+}
+
+fn register_portal(
+    server: &mut EspHttpServer<'static>,
+    networks: &[AccessPointInfo],
+    storage: &Storage,
+    saved: Sender<()>,
+    ip: Ipv4Addr,
+) -> Result<(), EspError> {
+    server.fn_handler("/", Method::Get, |request| {
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?;
+        response.write_all(PORTAL.as_bytes())?;
+        Ok::<(), EspIOError>(())
+    })?;
+    let networks = network_list(networks).to_string();
+    server.fn_handler("/networks", Method::Get, move |request| {
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "application/json")])?;
+        response.write_all(networks.as_bytes())?;
+        Ok::<(), EspIOError>(())
+    })?;
+    let storage = storage.clone();
+    server.fn_handler("/connect", Method::Post, move |mut request| {
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => {
+                let _ = exit_early(request, ApiError::BodyTooLarge);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let form = Query::parse(&String::from_utf8_lossy(&body));
+        let credentials = match form.get("ssid") {
+            Some(ssid) => Credentials {
+                ssid: ssid.to_string(),
+                password: form.get("password").unwrap_or_default().to_string(),
+                bssid: None,
+            },
+            None => {
+                let _ = exit_early(request, ApiError::MissingField("ssid"));
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        if let Err(error) = credentials.check().and_then(|_| credentials.save(&storage)) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "application/json")])?;
+        response.write_all(json!({ "ssid": credentials.ssid }).to_string().as_bytes())?;
+        let _ = saved.send(());
+        Ok::<(), EspIOError>(())
+    })?;
+    // Anything else, e.g. the pages phones fetch to check for a captive
+    // portal, is sent to the setup page
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |request| {
+        request.into_response(302, None, &[("Location", location.as_str())])?;
+        Ok::<(), EspIOError>(())
+    })?;
+    Ok(())
+}
+
+/// The networks found by the scan, strongest first, each listed once
+fn network_list(networks: &[AccessPointInfo]) -> serde_json::Value {
+    let mut networks: Vec<&AccessPointInfo> = networks
+        .iter()
+        .filter(|network| !network.ssid.is_empty())
+        .collect();
+    networks.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    let mut seen = Vec::new();
+    networks.retain(|network| {
+        let first = !seen.contains(&network.ssid);
+        seen.push(network.ssid.clone());
+        first
+    });
+    json!(networks
+        .iter()
+        .map(|network| json!({
+            "ssid": network.ssid.as_str(),
+            "rssi": network.signal_strength,
+            "secure": !matches!(network.auth_method, None | Some(AuthMethod::None)),
+        }))
+        .collect::<Vec<_>>())
+}
+
+/// Answers every DNS lookup for an address with `ip`
+///
+/// Never returns unless the port can't be bound.
+fn serve_dns(ip: Ipv4Addr) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 53))?;
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Couldn't receive DNS query: {}", e);
+                continue;
+            }
+        };
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            let _ = socket.send_to(&reply, from);
+        }
+    }
+}
+
+/// The reply to the query's first question, an A record pointing at `ip`
+/// if that's what was asked for and no records otherwise
+///
+/// Returns `None` for anything that isn't a query with a question.
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[4..6] == [0, 0] {
+        return None;
+    }
+    // The question's name is a list of labels ending in an empty one
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let question = query.get(12..end + 4)?;
+    let asks_for_address = question[question.len() - 4..] == [0, 1, 0, 1];
+
+    let mut reply = Vec::with_capacity(12 + question.len() + 16);
+    reply.extend_from_slice(&query[..2]);
+    // A response, keeping whether recursion was desired, with no error
+    reply.extend_from_slice(&[0x80 | (query[2] & 0x01), 0x80]);
+    reply.extend_from_slice(&[0, 1, 0, asks_for_address as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if asks_for_address {
+        // Points back at the question's name, type A, class IN, a minute
+        reply.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
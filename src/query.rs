@@ -0,0 +1,133 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// A request's query parameters
+///
+/// Names and values are percent-decoded, with `+` as a space, and names are
+/// lowercased so that `UUID=` and `uuid=` are the same parameter. The
+/// parameters as they were sent are kept too, since those are what gets
+/// signed.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    raw: Vec<(String, String)>,
+    decoded: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Parses the part of the uri after the `?`, if there is one
+    pub fn from_uri(uri: &str) -> Self {
+        match uri.split_once('?') {
+            Some((_, query)) => Self::parse(query),
+            None => Self::default(),
+        }
+    }
+
+    pub fn parse(query: &str) -> Self {
+        let raw: Vec<(String, String)> = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (pair.to_string(), String::new()),
+            })
+            .collect();
+        let decoded = raw
+            .iter()
+            .map(|(name, value)| (percent_decode(name).to_lowercase(), percent_decode(value)))
+            .collect();
+        Self { raw, decoded }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decoded.is_empty()
+    }
+
+    /// The decoded value of the first parameter with the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.decoded
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The parameters exactly as they were sent
+    pub fn raw(&self) -> Vec<(&str, &str)> {
+        self.raw
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+}
+
+/// Decodes `%XX` escapes and `+`, leaving malformed escapes as they are
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let high = bytes.get(i + 1).and_then(|byte| hex_value(*byte));
+                let low = bytes.get(i + 2).and_then(|byte| hex_value(*byte));
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high << 4 | low);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes every byte but `A-Z a-z 0-9 - . _ ~` as `%XX`, with uppercase
+/// hex digits
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|value| value as u8)
+}
+
+/// How a name given in a request is compared to a device's name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameMatching {
+    /// Byte for byte
+    Exact,
+    /// Ignoring case
+    #[default]
+    CaseInsensitive,
+    /// Ignoring case, surrounding and repeated whitespace, and differences in
+    /// how the same characters are encoded, e.g. a precomposed `é` and an `e`
+    /// followed by a combining accent
+    Normalized,
+}
+
+impl NameMatching {
+    pub fn matches(&self, name: &str, given: &str) -> bool {
+        match self {
+            NameMatching::Exact => name == given,
+            NameMatching::CaseInsensitive => name.to_lowercase() == given.to_lowercase(),
+            NameMatching::Normalized => normalize(name) == normalize(given),
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    let name: String = name.nfkc().collect::<String>().to_lowercase();
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
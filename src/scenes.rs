@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use device::{Action, Devices};
+
+use crate::command::apply_each;
+use crate::error::ApiError;
+use crate::fade::Fades;
+use crate::state::DeviceState;
+use crate::storage::Storage;
+
+/// NVS key the scenes are stored under
+const SCENES_KEY: &str = "scenes";
+
+/// The targets some devices were at when the scene was captured
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scene {
+    pub targets: HashMap<Uuid, usize>,
+}
+
+/// The JSON body accepted by `POST /scene`, e.g.
+/// `{"name": "movie", "uuids": ["...", "..."]}`
+#[derive(Debug, Deserialize)]
+pub struct CaptureRequest {
+    pub name: Option<String>,
+    /// The devices to capture, every device with a target if not given
+    #[serde(default)]
+    pub uuids: Option<Vec<Uuid>>,
+}
+
+/// Named scenes, kept in NVS so that they survive reboots
+#[derive(Clone)]
+pub struct Scenes {
+    scenes: Arc<Mutex<HashMap<String, Scene>>>,
+    storage: Storage,
+}
+
+impl Scenes {
+    /// Loads the scenes saved before the last reboot
+    pub fn load(storage: Storage) -> Self {
+        let scenes = match storage.load(SCENES_KEY) {
+            Ok(scenes) => scenes.unwrap_or_default(),
+            Err(e) => {
+                log::warn!("Couldn't load scenes: {}", e);
+                HashMap::new()
+            }
+        };
+        Self {
+            scenes: Arc::new(Mutex::new(scenes)),
+            storage,
+        }
+    }
+
+    /// Every scene by name
+    pub fn to_value(&self) -> Value {
+        json!(*self.scenes.lock().unwrap())
+    }
+
+    /// Saves the current targets of the devices as the scene, replacing any
+    /// scene of the same name
+    pub fn capture(
+        &self,
+        name: &str,
+        devices: &Devices,
+        uuids: Option<&[Uuid]>,
+    ) -> Result<Scene, ApiError> {
+        let targets = {
+            let devices_guard = devices.devices.lock().unwrap();
+            if let Some(uuids) = uuids {
+                if !uuids
+                    .iter()
+                    .all(|uuid| devices_guard.iter().any(|device| device.uuid == *uuid))
+                {
+                    return Err(ApiError::UnknownUuid);
+                }
+            }
+            devices_guard
+                .iter()
+                .filter(|device| uuids.map_or(true, |uuids| uuids.contains(&device.uuid)))
+                .filter_map(|device| {
+                    DeviceState::of(device)
+                        .target
+                        .map(|target| (device.uuid, target))
+                })
+                .collect()
+        };
+        let scene = Scene { targets };
+        self.change(|scenes| {
+            scenes.insert(name.to_string(), scene.clone());
+            Ok(())
+        })?;
+        Ok(scene)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), ApiError> {
+        self.change(|scenes| match scenes.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(ApiError::UnknownScene),
+        })
+    }
+
+    /// Sets every device in the scene back to its target
+    ///
+    /// All of the devices are set while holding their lock once, so that
+    /// they change together, fading over `fade` if given. Returns which
+    /// devices were set and which were skipped, the same way a group command
+    /// does.
+    pub fn recall(
+        &self,
+        name: &str,
+        devices: &Devices,
+        fades: &Fades,
+        fade: Option<Duration>,
+    ) -> Result<Value, ApiError> {
+        fades.check(fade)?;
+        let scene = self
+            .scenes
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(ApiError::UnknownScene)?;
+        let mut devices_guard = devices.devices.lock().unwrap();
+        let mut result = apply_each(
+            devices_guard.as_mut_slice(),
+            scene
+                .targets
+                .iter()
+                .map(|(uuid, target)| (*uuid, Action::Set(*target))),
+            fades,
+            fade,
+        );
+        result["scene"] = json!(name);
+        Ok(result)
+    }
+
+    /// Makes the change to a copy of the scenes, which replaces them only
+    /// once it's been saved
+    fn change(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, Scene>) -> Result<(), ApiError>,
+    ) -> Result<(), ApiError> {
+        let mut scenes = self.scenes.lock().unwrap();
+        let mut changed = scenes.clone();
+        f(&mut changed)?;
+        if let Err(e) = self.storage.save(SCENES_KEY, &changed) {
+            log::error!("Couldn't save scenes: {}", e);
+            return Err(ApiError::StorageFailed);
+        }
+        *scenes = changed;
+        Ok(())
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+
+use embedded_svc::{
+    http::{server::Request, Headers, Method},
+    io::{Read, Write},
+};
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer};
+use esp_idf_svc::io::EspIOError;
+use esp_idf_sys::EspError;
+
+use device::Devices;
+
+use crate::auth::Access;
+use crate::capabilities::capabilities;
+use crate::command::{self, CommandRequest};
+use crate::error::ApiError;
+use crate::lookup::DeviceSelector;
+use crate::output::OutputRequest;
+use crate::query::Query;
+use crate::scenes::{CaptureRequest, Scenes};
+use crate::signing::{json_params, Verifier};
+use crate::Node;
+
+/// Control panel served at `/`
+const PANEL: &str = include_str!("panel.html");
+
+/// Registers all of the node's HTTP handlers on the server
+pub fn register_handlers(
+    server: &mut EspHttpServer<'static>,
+    devices: &Devices,
+    node: &Node,
+    verifier: &Option<Verifier>,
+    scenes: &Scenes,
+) -> Result<(), EspError> {
+    let events = &node.events;
+    let fades = &node.fades;
+    let events_port = node.events_port;
+    server.fn_handler("/", Method::Get, |request| {
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?;
+        response.write_all(PANEL.as_bytes())?;
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let auth = node.auth.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/status", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Read, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        if query.is_empty() {
+            let _ = exit_early(request, ApiError::MissingQuery);
+            return Ok(());
+        }
+        let status = match (query.get("uuid"), query.get("device")) {
+            (None, None) => Err(ApiError::MissingField("device")),
+            (uuid, name) => DeviceSelector::parse(uuid, name, None).and_then(|selector| {
+                let devices_guard = devices_clone.devices.lock().unwrap();
+                resolver
+                    .resolve(&devices_guard, &selector)
+                    .map(|index| devices_guard[index].to_json())
+            }),
+        };
+        match status {
+            Ok(json) => {
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(json.as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let auth = node.auth.clone();
+    server.fn_handler("/devices", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Read, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let mut devices = HashMap::new();
+        {
+            for device in devices_clone.devices.lock().unwrap().iter() {
+                devices.insert(device.name.clone(), device.clone());
+            }
+        }
+        let payload = serde_json::json!(devices);
+        let mut response = request.into_ok_response()?;
+        response.write_all(payload.to_string().as_bytes())?;
+        //Ok(())
+        return Ok::<(), EspIOError>(());
+    })?;
+    let devices_clone = devices.clone();
+    let inputs = node.inputs.clone();
+    let auth = node.auth.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/capabilities", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Read, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        let payload = match (query.get("uuid"), query.get("device")) {
+            (None, None) => {
+                let devices_guard = devices_clone.devices.lock().unwrap();
+                Ok(serde_json::json!(devices_guard
+                    .iter()
+                    .map(|device| capabilities(device, &inputs))
+                    .collect::<Vec<_>>()))
+            }
+            (uuid, name) => DeviceSelector::parse(uuid, name, None).and_then(|selector| {
+                let devices_guard = devices_clone.devices.lock().unwrap();
+                resolver
+                    .resolve(&devices_guard, &selector)
+                    .map(|index| capabilities(&devices_guard[index], &inputs))
+            }),
+        };
+        match payload {
+            Ok(payload) => {
+                let mut response = request.into_ok_response()?;
+                response.write_all(payload.to_string().as_bytes())?;
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let events_clone = events.clone();
+    let fades_clone = fades.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/command", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        if query.is_empty() {
+            let _ = exit_early(request, ApiError::MissingQuery);
+            return Ok::<(), EspIOError>(());
+        }
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&query.raw()) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let target = match command::parse_target(query.get("target")) {
+            Ok(t) => t,
+            Err(error) => {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let action = match command::parse_action(query.get("action"), target) {
+            Ok(a) => a,
+            Err(error) => {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let fade = match command::parse_duration(query.get("duration_ms"))
+            .and_then(|duration_ms| command::check_fade(&action, duration_ms))
+        {
+            Ok(f) => f,
+            Err(error) => {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let selector =
+            match DeviceSelector::parse(query.get("uuid"), query.get("device"), query.get("group"))
+            {
+                Ok(selector) => selector,
+                Err(error) => {
+                    let _ = exit_early(request, error);
+                    return Ok::<(), EspIOError>(());
+                }
+            };
+        match command::apply(
+            &devices_clone,
+            &resolver,
+            &fades_clone,
+            &selector,
+            action,
+            fade,
+        ) {
+            Ok(json) => {
+                events_clone.notify();
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(json.as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let events_clone = events.clone();
+    let fades_clone = fades.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/command", Method::Post, move |mut request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => {
+                let _ = exit_early(request, ApiError::BodyTooLarge);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let command: CommandRequest = match serde_json::from_slice(&body) {
+            Ok(c) => c,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&command.params()) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let (selector, action, fade) = match command.validate() {
+            Ok(c) => c,
+            Err(error) => {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        match command::apply(
+            &devices_clone,
+            &resolver,
+            &fades_clone,
+            &selector,
+            action,
+            fade,
+        ) {
+            Ok(json) => {
+                events_clone.notify();
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(json.as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let events_clone = events.clone();
+    let fades_clone = fades.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/commands", Method::Post, move |mut request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => {
+                let _ = exit_early(request, ApiError::BodyTooLarge);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let commands: Vec<CommandRequest> = match serde_json::from_slice(&body) {
+            Ok(c) => c,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let results = command::apply_all(
+            &devices_clone,
+            &resolver,
+            &fades_clone,
+            &commands,
+            |command| match &verifier_clone {
+                Some(verifier) => verifier.verify(&command.params()),
+                None => Ok(()),
+            },
+        );
+        events_clone.notify();
+        let payload = serde_json::json!(results);
+        let mut response = request.into_ok_response()?;
+        response.write_all(payload.to_string().as_bytes())?;
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let events_clone = events.clone();
+    let fades_clone = fades.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let scenes_clone = scenes.clone();
+    server.fn_handler("/scene", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&query.raw()) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let fade = match command::parse_duration(query.get("duration_ms"))
+            .and_then(command::fade_duration)
+        {
+            Ok(f) => f,
+            Err(error) => {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let result = match query.get("name") {
+            Some(name) => scenes_clone.recall(name, &devices_clone, &fades_clone, fade),
+            None => Err(ApiError::MissingField("name")),
+        };
+        match result {
+            Ok(result) => {
+                events_clone.notify();
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(result.to_string().as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let scenes_clone = scenes.clone();
+    server.fn_handler("/scene", Method::Post, move |mut request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => {
+                let _ = exit_early(request, ApiError::BodyTooLarge);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let body: serde_json::Value = match serde_json::from_slice(&body) {
+            Ok(b) => b,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&json_params(&body)) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let capture: CaptureRequest = match serde_json::from_value(body) {
+            Ok(c) => c,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let result = match capture.name.as_deref() {
+            Some(name) if !name.is_empty() => scenes_clone
+                .capture(name, &devices_clone, capture.uuids.as_deref())
+                .map(|scene| serde_json::json!({ "name": name, "scene": scene })),
+            _ => Err(ApiError::MissingField("name")),
+        };
+        match result {
+            Ok(result) => {
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(result.to_string().as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let scenes_clone = scenes.clone();
+    server.fn_handler("/scene", Method::Delete, move |request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&query.raw()) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let result = match query.get("name") {
+            Some(name) => scenes_clone
+                .remove(name)
+                .map(|_| serde_json::json!({ "removed": name })),
+            None => Err(ApiError::MissingField("name")),
+        };
+        match result {
+            Ok(result) => {
+                let mut response = request.into_ok_response()?;
+                let _ = response.write_all(result.to_string().as_bytes());
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let auth = node.auth.clone();
+    let scenes_clone = scenes.clone();
+    server.fn_handler("/scenes", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Read, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let mut response = request.into_ok_response()?;
+        response.write_all(scenes_clone.to_value().to_string().as_bytes())?;
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let outputs = node.outputs.clone();
+    let auth = node.auth.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/output", Method::Get, move |request| {
+        if let Err(error) = auth.check(Access::Read, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let query = Query::from_uri(request.uri());
+        let output = match (query.get("uuid"), query.get("device")) {
+            (None, None) => Err(ApiError::MissingField("device")),
+            (uuid, name) => DeviceSelector::parse(uuid, name, None).and_then(|selector| {
+                let devices_guard = devices_clone.devices.lock().unwrap();
+                resolver
+                    .resolve(&devices_guard, &selector)
+                    .map(|index| outputs.to_value(&devices_guard[index].uuid))
+            }),
+        };
+        match output {
+            Ok(output) => {
+                let mut response = request.into_ok_response()?;
+                response.write_all(output.to_string().as_bytes())?;
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    let devices_clone = devices.clone();
+    let outputs = node.outputs.clone();
+    let auth = node.auth.clone();
+    let verifier_clone = verifier.clone();
+    let resolver = node.resolver();
+    server.fn_handler("/output", Method::Post, move |mut request| {
+        if let Err(error) = auth.check(Access::Write, request.header("Authorization")) {
+            let _ = exit_early(request, error);
+            return Ok::<(), EspIOError>(());
+        }
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => {
+                let _ = exit_early(request, ApiError::BodyTooLarge);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let body: serde_json::Value = match serde_json::from_slice(&body) {
+            Ok(b) => b,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        if let Some(verifier) = &verifier_clone {
+            if let Err(error) = verifier.verify(&json_params(&body)) {
+                let _ = exit_early(request, error);
+                return Ok::<(), EspIOError>(());
+            }
+        }
+        let output: OutputRequest = match serde_json::from_value(body) {
+            Ok(o) => o,
+            Err(_) => {
+                let _ = exit_early(request, ApiError::InvalidJson);
+                return Ok::<(), EspIOError>(());
+            }
+        };
+        let result = DeviceSelector::parse(output.uuid.as_deref(), output.device.as_deref(), None)
+            .and_then(|selector| {
+                let devices_guard = devices_clone.devices.lock().unwrap();
+                let uuid = devices_guard[resolver.resolve(&devices_guard, &selector)?].uuid;
+                outputs.update(uuid, &output)?;
+                Ok(outputs.to_value(&uuid))
+            });
+        match result {
+            Ok(result) => {
+                let mut response = request.into_ok_response()?;
+                response.write_all(result.to_string().as_bytes())?;
+            }
+            Err(error) => {
+                let _ = exit_early(request, error);
+            }
+        }
+        Ok::<(), EspIOError>(())
+    })?;
+    server.fn_handler("/events", Method::Get, move |request| {
+        // The stream is served on its own port, see `events::serve_events`
+        let host = request.header("Host").unwrap_or_default();
+        let host = host.split(':').next().unwrap_or_default();
+        let query = request.uri().split_once('?').map(|(_, query)| query);
+        let location = match query {
+            Some(query) => format!("http://{}:{}/events?{}", host, events_port, query),
+            None => format!("http://{}:{}/events", host, events_port),
+        };
+        request.into_response(
+            307,
+            None,
+            &[
+                ("Location", location.as_str()),
+                ("Access-Control-Allow-Origin", "*"),
+            ],
+        )?;
+        Ok::<(), EspIOError>(())
+    })?;
+    Ok(())
+}
+
+/// Largest request body that will be read into memory
+const MAX_BODY_LEN: usize = 4096;
+
+/// Reads the whole request body
+///
+/// Returns `None` if the body is larger than `MAX_BODY_LEN`
+pub(crate) fn read_body<'a>(
+    request: &mut Request<&mut EspHttpConnection<'a>>,
+) -> Result<Option<Vec<u8>>, EspIOError> {
+    let len = request.content_len().unwrap_or(0) as usize;
+    if len > MAX_BODY_LEN {
+        return Ok(None);
+    }
+    let mut body = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        let count = request.read(&mut body[read..])?;
+        if count == 0 {
+            break;
+        }
+        read += count;
+    }
+    body.truncate(read);
+    Ok(Some(body))
+}
+
+pub(crate) fn exit_early<'a>(
+    request: Request<&mut EspHttpConnection<'a>>,
+    error: ApiError,
+) -> Result<(), EspIOError> {
+    let mut response = request.into_response(
+        error.status(),
+        None,
+        &[("Content-Type", "application/json")],
+    )?;
+    let _ = response.write_all(error.to_json().as_bytes());
+    Ok(())
+}
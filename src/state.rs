@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use device::{Action, Device, Devices};
+
+use crate::command::{supports, MAX_TARGET};
+use crate::events::DeviceChange;
+use crate::storage::Storage;
+
+/// NVS key the devices' states are stored under
+const STATES_KEY: &str = "states";
+
+/// The part of a device's state that can be put back by having it take
+/// actions
+///
+/// Read out of the device's json, since that's what `device` exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub target: Option<usize>,
+    #[serde(default)]
+    pub reversed: Option<bool>,
+}
+
+impl DeviceState {
+    pub fn of(device: &Device) -> Self {
+        Self::from_json(&device.to_json())
+    }
+
+    pub fn from_json(json: &str) -> Self {
+        let json: Value = serde_json::from_str(json).unwrap_or_default();
+        Self {
+            target: json["target"].as_u64().map(|target| target as usize),
+            reversed: json["reversed"].as_bool(),
+        }
+    }
+
+    /// Has the device take whichever actions bring it to this state
+    pub fn apply_to(&self, device: &mut Device) {
+        if let Some(target) = self.target {
+            let action = Action::Set(target.min(MAX_TARGET));
+            if supports(device, &action) {
+                let _ = device.take_action(action);
+            }
+        }
+        if let (Some(reversed), Some(current)) = (self.reversed, DeviceState::of(device).reversed) {
+            if reversed != current && supports(device, &Action::Reverse) {
+                let _ = device.take_action(Action::Reverse);
+            }
+        }
+    }
+}
+
+/// What a device is set to when the node starts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOn {
+    /// Whatever it was at before the node restarted
+    #[default]
+    Restore,
+    Off,
+    /// Always this target
+    Target(usize),
+}
+
+/// Sets every device according to its `PowerOn`, devices that aren't in
+/// `power_on` are restored
+pub fn restore(devices: &Devices, storage: &Storage, power_on: &HashMap<Uuid, PowerOn>) {
+    let saved: HashMap<Uuid, DeviceState> = match storage.load(STATES_KEY) {
+        Ok(saved) => saved.unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Couldn't load device states: {}", e);
+            HashMap::new()
+        }
+    };
+    for device in devices.devices.lock().unwrap().iter_mut() {
+        let state = match power_on.get(&device.uuid).copied().unwrap_or_default() {
+            PowerOn::Restore => match saved.get(&device.uuid) {
+                Some(state) => *state,
+                None => continue,
+            },
+            PowerOn::Off => DeviceState {
+                target: Some(0),
+                reversed: None,
+            },
+            PowerOn::Target(target) => DeviceState {
+                target: Some(target),
+                reversed: None,
+            },
+        };
+        state.apply_to(device);
+    }
+}
+
+/// Saves the devices' states as they change, once they've stayed the same
+/// for `delay`
+///
+/// Waiting keeps something like an encoder being spun from writing to flash
+/// on every step. Returns once `changes` hangs up, so it should be given its
+/// own thread.
+pub fn persist(changes: Receiver<DeviceChange>, storage: Storage, delay: Duration) {
+    let mut states: HashMap<Uuid, DeviceState> =
+        storage.load(STATES_KEY).ok().flatten().unwrap_or_default();
+    while let Ok(change) = changes.recv() {
+        let mut changed = record(&mut states, &change);
+        let hung_up = loop {
+            match changes.recv_timeout(delay) {
+                Ok(change) => changed |= record(&mut states, &change),
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break true,
+            }
+        };
+        if changed {
+            if let Err(e) = storage.save(STATES_KEY, &states) {
+                log::warn!("Couldn't save device states: {}", e);
+            }
+        }
+        if hung_up {
+            return;
+        }
+    }
+}
+
+/// Returns whether the device's state is different from the one recorded
+fn record(states: &mut HashMap<Uuid, DeviceState>, change: &DeviceChange) -> bool {
+    let state = DeviceState::from_json(&change.json);
+    states.insert(change.uuid, state) != Some(state)
+}
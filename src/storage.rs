@@ -0,0 +1,51 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Values kept in NVS as JSON, so they survive reboots
+///
+/// Keys can be at most 15 bytes long.
+#[derive(Clone)]
+pub struct Storage {
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition, namespace: &str) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, namespace, true)?;
+        Ok(Self {
+            nvs: Arc::new(Mutex::new(nvs)),
+        })
+    }
+
+    /// Returns `None` if nothing is stored under the key, or if what is
+    /// stored can't be read as a `T`
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, EspError> {
+        let nvs = self.nvs.lock().unwrap();
+        let len = match nvs.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0; len];
+        let value = match nvs.get_blob(key, &mut buf)? {
+            Some(blob) => serde_json::from_slice(blob).ok(),
+            None => None,
+        };
+        if value.is_none() {
+            log::warn!("Couldn't read stored {}", key);
+        }
+        Ok(value)
+    }
+
+    pub fn save<T: Serialize>(&self, key: &str, value: &T) -> Result<(), EspError> {
+        let blob = serde_json::to_vec(value).unwrap_or_default();
+        self.nvs.lock().unwrap().set_blob(key, &blob)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), EspError> {
+        self.nvs.lock().unwrap().remove(key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use std::net::Ipv4Addr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::modem::Modem;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::ipv4::{self, ClientSettings, Mask, Subnet};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{AccessPointInfo, ClientConfiguration, Configuration, EspWifi, WifiDriver};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::WifiLink;
+use crate::error::{ApiError, NodeError};
+use crate::storage::Storage;
+
+/// NVS key the credentials saved by the provisioning portal are stored under
+const CREDENTIALS_KEY: &str = "wifi";
+/// How long each attempt to join a network is given
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest hostname the station interface takes
+const MAX_HOSTNAME_LEN: usize = 32;
+
+/// Fixed IPv4 settings for the station interface, used instead of DHCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticIp {
+    pub address: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    /// As a prefix length, e.g. 24 for 255.255.255.0
+    pub netmask: u8,
+    pub dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    fn settings(&self) -> Result<ClientSettings, NodeError> {
+        if self.netmask > 32 {
+            return Err(NodeError::Config("netmask is longer than 32 bits"));
+        }
+        Ok(ClientSettings {
+            ip: self.address.octets().into(),
+            subnet: Subnet {
+                gateway: self.gateway.octets().into(),
+                mask: Mask(self.netmask),
+            },
+            dns: self.dns.map(|dns| dns.octets().into()),
+            secondary_dns: self.secondary_dns.map(|dns| dns.octets().into()),
+        })
+    }
+}
+
+/// Creates the wifi driver, with the station interface using `static_ip`
+/// instead of DHCP if it's given, and going by `hostname`
+pub fn create(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    static_ip: Option<&StaticIp>,
+    hostname: &str,
+) -> Result<EspWifi<'static>, NodeError> {
+    let driver = WifiDriver::new(modem, sys_loop, Some(nvs)).map_err(NodeError::Wifi)?;
+    let mut sta_configuration = NetifConfiguration::wifi_default_client();
+    if let Some(static_ip) = static_ip {
+        sta_configuration.ip_configuration =
+            ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(static_ip.settings()?));
+    }
+    let sta_netif = EspNetif::new_with_conf(&sta_configuration).map_err(NodeError::Wifi)?;
+    let ap_netif = EspNetif::new(NetifStack::Ap).map_err(NodeError::Wifi)?;
+    let mut wifi = EspWifi::wrap_all(driver, sta_netif, ap_netif).map_err(NodeError::Wifi)?;
+    if !is_valid_hostname(hostname) {
+        return Err(NodeError::Config(
+            "hostname must be 1 to 32 letters, digits or inner hyphens",
+        ));
+    }
+    wifi.sta_netif_mut()
+        .set_hostname(hostname)
+        .map_err(NodeError::Wifi)?;
+    Ok(wifi)
+}
+
+/// Whether `hostname` is a single DNS label the station interface takes
+fn is_valid_hostname(hostname: &str) -> bool {
+    (1..=MAX_HOSTNAME_LEN).contains(&hostname.len())
+        && hostname
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+}
+
+/// A hostname made from a free-form name, e.g. `living-room` for
+/// "Living Room", or `node` if nothing in the name can be used
+pub fn hostname_from(name: &str) -> String {
+    let mut hostname = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            hostname.push(c.to_ascii_lowercase());
+        } else if !hostname.is_empty() && !hostname.ends_with('-') {
+            hostname.push('-');
+        }
+    }
+    hostname.truncate(MAX_HOSTNAME_LEN);
+    let hostname = hostname.trim_end_matches('-');
+    match hostname.is_empty() {
+        true => "node".to_string(),
+        false => hostname.to_string(),
+    }
+}
+
+/// What's needed to join a network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub ssid: String,
+    pub password: String,
+    /// Only join the access point with this BSSID, for when several share
+    /// the ssid
+    #[serde(default)]
+    pub bssid: Option<[u8; 6]>,
+}
+
+impl Credentials {
+    /// The credentials saved by the provisioning portal, if there are any
+    pub fn load(storage: &Storage) -> Option<Self> {
+        match storage.load(CREDENTIALS_KEY) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                log::warn!("Couldn't load wifi credentials: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, storage: &Storage) -> Result<(), ApiError> {
+        storage.save(CREDENTIALS_KEY, self).map_err(|e| {
+            log::error!("Couldn't save wifi credentials: {}", e);
+            ApiError::StorageFailed
+        })
+    }
+
+    /// Fails on an ssid or password the wifi driver won't take
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            return Err(ApiError::InvalidField("ssid"));
+        }
+        if !self.password.is_empty() && !(8..=64).contains(&self.password.len()) {
+            return Err(ApiError::InvalidField("password"));
+        }
+        Ok(())
+    }
+
+    pub fn client_configuration(&self) -> Result<ClientConfiguration, NodeError> {
+        let ssid = heapless::String::try_from(self.ssid.as_str())
+            .map_err(|_| NodeError::Config("ssid is longer than 32 bytes"))?;
+        let password = heapless::String::try_from(self.password.as_str())
+            .map_err(|_| NodeError::Config("password is longer than 64 bytes"))?;
+        Ok(ClientConfiguration {
+            ssid,
+            password,
+            bssid: self.bssid,
+            ..Default::default()
+        })
+    }
+
+    /// Whether the scanned access point is this network
+    pub fn matches(&self, access_point: &AccessPointInfo) -> bool {
+        access_point.ssid.as_str() == self.ssid
+            && self.bssid.map_or(true, |bssid| bssid == access_point.bssid)
+    }
+}
+
+/// Joins the first of `networks` that it can, returning which one
+///
+/// The networks a scan finds are tried first, strongest first, then the
+/// ones it didn't, which could be hidden. Otherwise, and when the scan
+/// fails, earlier networks in the list are tried first. Each network is
+/// tried `attempts` times.
+pub fn connect_any(
+    wifi: &mut EspWifi<'static>,
+    networks: &[Credentials],
+    attempts: u32,
+) -> Result<Option<Credentials>, NodeError> {
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .map_err(NodeError::Wifi)?;
+    wifi.start().map_err(NodeError::Wifi)?;
+    let scanned = match wifi.scan() {
+        Ok(scanned) => scanned,
+        Err(e) => {
+            log::warn!("Couldn't scan for networks: {}", e);
+            Vec::new()
+        }
+    };
+    for credentials in by_preference(networks, &scanned) {
+        if connect(wifi, &credentials, attempts)? {
+            return Ok(Some(credentials));
+        }
+    }
+    Ok(None)
+}
+
+/// `networks` in the order they should be tried, see `connect_any`
+pub(crate) fn by_preference(
+    networks: &[Credentials],
+    scanned: &[AccessPointInfo],
+) -> Vec<Credentials> {
+    let mut found: Vec<(i8, Credentials)> = Vec::new();
+    let mut missing: Vec<Credentials> = Vec::new();
+    for credentials in networks {
+        let strongest = scanned
+            .iter()
+            .filter(|access_point| credentials.matches(access_point))
+            .max_by_key(|access_point| access_point.signal_strength);
+        match strongest {
+            Some(access_point) => found.push((access_point.signal_strength, credentials.clone())),
+            None => missing.push(credentials.clone()),
+        }
+    }
+    // Stable, so networks as strong as each other keep their priority
+    found.sort_by(|(a, _), (b, _)| b.cmp(a));
+    found
+        .into_iter()
+        .map(|(_, credentials)| credentials)
+        .chain(missing)
+        .collect()
+}
+
+/// Tries to join the network up to `attempts` times, returning whether it did
+pub fn connect(
+    wifi: &mut EspWifi<'static>,
+    credentials: &Credentials,
+    attempts: u32,
+) -> Result<bool, NodeError> {
+    wifi.set_configuration(&Configuration::Client(credentials.client_configuration()?))
+        .map_err(NodeError::Wifi)?;
+    wifi.start().map_err(NodeError::Wifi)?;
+    for attempt in 1..=attempts {
+        if let Err(e) = wifi.connect() {
+            log::warn!("Couldn't connect to {}: {}", credentials.ssid, e);
+        }
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        while Instant::now() < deadline {
+            if wifi.is_connected().map_err(NodeError::Wifi)? {
+                return Ok(true);
+            }
+            sleep(Duration::from_millis(500));
+        }
+        log::warn!(
+            "Attempt {} of {} to join {} failed",
+            attempt,
+            attempts,
+            credentials.ssid
+        );
+        let _ = wifi.disconnect();
+    }
+    Ok(false)
+}
+
+/// The node's wifi, moving through its known networks as they fail
+///
+/// Each time it has been through all of them, it scans again so that the
+/// next round starts with the strongest, the same way `connect_any` does.
+pub struct NodeWifi {
+    wifi: EspWifi<'static>,
+    networks: Vec<Credentials>,
+    order: Vec<Credentials>,
+    next: usize,
+}
+
+impl NodeWifi {
+    pub fn new(wifi: EspWifi<'static>, networks: Vec<Credentials>) -> Self {
+        Self {
+            wifi,
+            order: networks.clone(),
+            networks,
+            next: 0,
+        }
+    }
+}
+
+impl WifiLink for NodeWifi {
+    type Error = NodeError;
+
+    fn connect(&mut self) -> Result<(), NodeError> {
+        self.wifi.connect().map_err(NodeError::Wifi)
+    }
+
+    fn disconnect(&mut self) -> Result<(), NodeError> {
+        self.wifi.disconnect().map_err(NodeError::Wifi)
+    }
+
+    fn is_connected(&self) -> Result<bool, NodeError> {
+        self.wifi.is_connected().map_err(NodeError::Wifi)
+    }
+
+    fn is_up(&self) -> Result<bool, NodeError> {
+        self.wifi.is_up().map_err(NodeError::Wifi)
+    }
+
+    fn next_network(&mut self) -> Result<(), NodeError> {
+        if self.networks.len() < 2 {
+            return Ok(());
+        }
+        if self.next == 0 {
+            match self.wifi.scan() {
+                Ok(scanned) => self.order = by_preference(&self.networks, &scanned),
+                Err(e) => log::warn!("Couldn't scan for networks: {}", e),
+            }
+        }
+        let credentials = &self.order[self.next];
+        self.next = (self.next + 1) % self.order.len();
+        log::info!("Trying {} next", credentials.ssid);
+        self.wifi
+            .set_configuration(&Configuration::Client(credentials.client_configuration()?))
+            .map_err(NodeError::Wifi)
+    }
+}
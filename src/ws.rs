@@ -0,0 +1,181 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::{ws::EspHttpWsDetachedSender, EspHttpServer};
+use esp_idf_sys::{esp_err_t, EspError, ESP_ERR_INVALID_SIZE};
+use serde_json::json;
+
+use device::Devices;
+
+use crate::auth::{Access, Auth};
+use crate::command::{self, CommandRequest};
+use crate::error::{ApiError, NodeError};
+use crate::events::DeviceEvents;
+use crate::fade::Fades;
+use crate::lookup::Resolver;
+use crate::signing::Verifier;
+use crate::THREAD_STACK_SIZE;
+
+/// Largest frame that will be read from a client
+const MAX_FRAME_LEN: usize = 1024;
+
+/// Senders for every open websocket, keyed by their session
+///
+/// Each sender has its own lock, so that changes can be sent without holding
+/// the list's. Sending waits on the http server's task, which also takes the
+/// list's lock when sessions open and close.
+type Clients = Arc<Mutex<Vec<(i32, Arc<Mutex<EspHttpWsDetachedSender>>)>>>;
+
+/// Registers the `/ws` control channel on the server
+///
+/// Clients send the same JSON as the body of `POST /command`, along with a
+/// `"token"` when `Node::auth` has any. The sender
+/// gets the device's json or an error back right away, and every change to
+/// any device, whoever made it, is sent to all of the connected clients.
+///
+/// When `Auth::protect_reads` is set, a client only gets changes once one of
+/// its frames has carried a token that may read. A frame with nothing but
+/// the `"token"` does just that, and is answered with `{"watching": true}`.
+///
+/// Needs `CONFIG_HTTPD_WS_SUPPORT=y` in the firmware's sdkconfig.
+pub fn register(
+    server: &mut EspHttpServer<'static>,
+    devices: &Devices,
+    events: &DeviceEvents,
+    fades: &Fades,
+    auth: &Auth,
+    verifier: &Option<Verifier>,
+    resolver: &Resolver,
+) -> Result<(), NodeError> {
+    let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+    // Clients that haven't shown they may read yet
+    let pending: Clients = Arc::new(Mutex::new(Vec::new()));
+
+    let clients_clone = clients.clone();
+    let changes = events.subscribe();
+    thread::Builder::new()
+        .stack_size(THREAD_STACK_SIZE)
+        .spawn(move || {
+            for change in changes.iter() {
+                let senders = clients_clone.lock().unwrap().clone();
+                let failed: Vec<i32> = senders
+                    .iter()
+                    .filter(|(_, sender)| {
+                        sender
+                            .lock()
+                            .unwrap()
+                            .send(FrameType::Text(false), change.json.as_bytes())
+                            .is_err()
+                    })
+                    .map(|(session, _)| *session)
+                    .collect();
+                if !failed.is_empty() {
+                    clients_clone
+                        .lock()
+                        .unwrap()
+                        .retain(|(session, _)| !failed.contains(session));
+                }
+            }
+        })
+        .map_err(NodeError::Spawn)?;
+
+    let devices_clone = devices.clone();
+    let events_clone = events.clone();
+    let fades = fades.clone();
+    let auth = auth.clone();
+    let verifier = verifier.clone();
+    let resolver = resolver.clone();
+    server
+        .ws_handler("/ws", move |ws| {
+            let session = ws.session();
+            if ws.is_new() {
+                let sender = Arc::new(Mutex::new(ws.create_detached_sender()?));
+                if auth.check_token(Access::Read, None).is_ok() {
+                    clients.lock().unwrap().push((session, sender));
+                } else {
+                    pending.lock().unwrap().push((session, sender));
+                }
+                return Ok::<(), EspError>(());
+            } else if ws.is_closed() {
+                clients.lock().unwrap().retain(|(s, _)| *s != session);
+                pending.lock().unwrap().retain(|(s, _)| *s != session);
+                return Ok(());
+            }
+
+            let (_, len) = ws.recv(&mut [])?;
+            if len > MAX_FRAME_LEN {
+                // The frame is left unread, so the connection has to be closed
+                ws.send(
+                    FrameType::Text(false),
+                    ApiError::BodyTooLarge.to_json().as_bytes(),
+                )?;
+                return Err(EspError::from(ESP_ERR_INVALID_SIZE as esp_err_t).unwrap());
+            }
+            let mut frame = vec![0; len];
+            let (frame_type, _) = ws.recv(&mut frame)?;
+            if !matches!(frame_type, FrameType::Text(_)) {
+                return Ok(());
+            }
+
+            let command = match serde_json::from_slice::<CommandRequest>(&frame) {
+                Ok(command) => command,
+                Err(_) => {
+                    ws.send(
+                        FrameType::Text(false),
+                        ApiError::InvalidJson.to_json().as_bytes(),
+                    )?;
+                    return Ok(());
+                }
+            };
+            let can_read = auth.check_token(Access::Read, command.token.as_deref());
+            if can_read.is_ok() {
+                let mut pending = pending.lock().unwrap();
+                if let Some(index) = pending.iter().position(|(s, _)| *s == session) {
+                    clients.lock().unwrap().push(pending.remove(index));
+                }
+            }
+            if is_token_only(&command) {
+                let reply = match can_read {
+                    Ok(()) => json!({ "watching": true }).to_string(),
+                    Err(error) => error.to_json(),
+                };
+                ws.send(FrameType::Text(false), reply.as_bytes())?;
+                return Ok(());
+            }
+
+            let result = auth
+                .check_token(Access::Write, command.token.as_deref())
+                .and_then(|_| {
+                    if let Some(verifier) = &verifier {
+                        verifier.verify(&command.params())?;
+                    }
+                    command.validate()
+                })
+                .and_then(|(selector, action, fade)| {
+                    command::apply(&devices_clone, &resolver, &fades, &selector, action, fade)
+                });
+            match result {
+                Ok(json) => {
+                    events_clone.notify();
+                    ws.send(FrameType::Text(false), json.as_bytes())?;
+                }
+                Err(error) => {
+                    ws.send(FrameType::Text(false), error.to_json().as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(NodeError::Server)?;
+    Ok(())
+}
+
+/// Whether the frame only carries a token, to start getting changes
+fn is_token_only(command: &CommandRequest) -> bool {
+    command.action.is_none()
+        && command.uuid.is_none()
+        && command.device.is_none()
+        && command.group.is_none()
+}